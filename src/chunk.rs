@@ -1,12 +1,306 @@
-use crate::crypto::Hash;
+use std::cmp::min;
+use std::fmt;
+use std::str::FromStr;
 
+use crate::crypto::{hash, Hash, Key};
+use crate::remote::{AccountId, ProviderError};
+
+/// Default size of chunks files are split into
 pub const CHUNK_SIZE: usize = 512;
-pub type Data = [u8; CHUNK_SIZE];
+/// Smallest average size of content-defined chunks, the cut mask of smaller
+/// ones has too few bits to find boundaries by the content
+pub const MIN_CDC_AVG: usize = 64;
+pub type Data = Vec<u8>;
+
+/// Compression of chunk data as it is published
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    None,
+    Zstd,
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Codec::None => write!(f, "none"),
+            Codec::Zstd => write!(f, "zstd"),
+        }
+    }
+}
 
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Codec::None),
+            "zstd" => Ok(Codec::Zstd),
+            _ => Err(format!("unknown codec {}", s)),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Chunk {
     pub hash: Hash,
+    /// Name of the remote object holding the chunk
+    pub name: Hash,
     pub chunk: Data,
     pub idx: u64,
+    /// Codec `chunk` is compressed with
+    pub codec: Codec,
 }
 
 pub type Chunks = Vec<Chunk>;
+
+/// Published chunk along with the account it lives on
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Stored {
+    pub hash: Hash,
+    /// Name of the remote object holding the chunk
+    pub name: Hash,
+    pub account: AccountId,
+    /// Chunk length, i.e. distance to the next chunk boundary in the file
+    pub len: usize,
+    /// Codec the chunk was published with
+    pub codec: Codec,
+}
+
+impl Stored {
+    /// Checks that received data ends exactly at the chunk boundary and is
+    /// the content the chunk hash was computed from
+    pub fn check(&self, data: &[u8]) -> Result<(), ProviderError> {
+        if data.len() != self.len || hash(data) != self.hash {
+            Err(ProviderError::Mismatch(self.hash.clone()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Strategy of splitting files into chunks
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Chunking {
+    /// Chunks of the same size, the last one may be shorter
+    Fixed(usize),
+    /// Content-defined chunks of `min..=max` bytes, `avg` bytes on average
+    ///
+    /// Boundaries depend on the content around them only, so inserting data
+    /// into a file changes the chunks near the insertion point only.
+    Cdc { min: usize, avg: usize, max: usize },
+}
+
+impl Default for Chunking {
+    fn default() -> Self {
+        Chunking::Fixed(CHUNK_SIZE)
+    }
+}
+
+impl Chunking {
+    /// Content-defined chunking with the recommended bounds around `avg`,
+    /// which must be at least `MIN_CDC_AVG`
+    pub fn cdc(avg: usize) -> Chunking {
+        assert!(
+            avg >= MIN_CDC_AVG,
+            "Average chunk size is below {}",
+            MIN_CDC_AVG
+        );
+        Chunking::Cdc {
+            min: (avg / 4).max(1),
+            avg,
+            max: avg * 4,
+        }
+    }
+
+    /// Upper bound of the chunk size
+    pub fn max_len(&self) -> usize {
+        match *self {
+            Chunking::Fixed(size) => size,
+            Chunking::Cdc { max, .. } => max,
+        }
+    }
+
+    /// Splits content into chunks
+    pub fn split(&self, s: &[u8]) -> Chunks {
+        let mut chunks = Vec::new();
+        let mut rest = s;
+        let gear = gear();
+        while !rest.is_empty() {
+            let len = match *self {
+                Chunking::Fixed(size) => min(size, rest.len()),
+                Chunking::Cdc { min, avg, max } => cut(rest, min, avg, max, &gear),
+            };
+            let (c, r) = rest.split_at(len);
+            let hash = hash(c);
+            chunks.push(Chunk {
+                name: hash.clone(),
+                hash,
+                chunk: c.to_vec(),
+                idx: chunks.len() as u64,
+                codec: Codec::None,
+            });
+            rest = r;
+        }
+        chunks
+    }
+}
+
+/// Names chunks by their hashes keyed with the stash key, so the provider
+/// can't tell whether a known content is stored
+pub fn name_with(chunks: &mut [Chunk], key: &Key) {
+    for c in chunks {
+        c.name = key.name(&c.hash);
+    }
+}
+
+impl fmt::Display for Chunking {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Chunking::Fixed(size) => write!(f, "fixed:{}", size),
+            Chunking::Cdc { min, avg, max } => write!(f, "cdc:{}:{}:{}", min, avg, max),
+        }
+    }
+}
+
+impl FromStr for Chunking {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let kind = parts.next().unwrap_or_default();
+        let sizes = parts
+            .map(|p| p.parse().map_err(|_| format!("bad chunk size in {}", s)))
+            .collect::<Result<Vec<usize>, _>>()?;
+        match (kind, sizes.as_slice()) {
+            ("fixed", [size]) if *size > 0 => Ok(Chunking::Fixed(*size)),
+            ("cdc", [min, avg, max])
+                if 0 < *min && min <= avg && avg <= max && *avg >= MIN_CDC_AVG =>
+            {
+                Ok(Chunking::Cdc {
+                    min: *min,
+                    avg: *avg,
+                    max: *max,
+                })
+            }
+            _ => Err(format!("unknown chunking {}", s)),
+        }
+    }
+}
+
+/// Random values of bytes for the gear rolling hash, generated with
+/// splitmix64 to be the same on every run
+fn gear() -> Vec<u64> {
+    let mut state = 0u64;
+    (0..256)
+        .map(|_| {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        })
+        .collect()
+}
+
+/// Finds the end of the first chunk with FastCDC
+///
+/// The gear hash is tested against a mask of the high bits. A stricter mask
+/// is used before the `avg` offset and a looser one after it, which keeps
+/// chunk sizes close to the average.
+fn cut(s: &[u8], min_len: usize, avg: usize, max_len: usize, gear: &[u64]) -> usize {
+    if s.len() <= min_len {
+        return s.len();
+    }
+    let end = min(s.len(), max_len);
+    let normal = min(avg, end);
+    let bits = 63 - (avg as u64).leading_zeros();
+    let mask = |bits: u32| !(u64::MAX >> bits);
+    let (strict, loose) = (mask(bits + 1), mask(bits.saturating_sub(1)));
+    let mut h = 0u64;
+    for (i, b) in s.iter().enumerate().take(end).skip(min_len) {
+        h = (h << 1).wrapping_add(gear[*b as usize]);
+        let m = if i < normal { strict } else { loose };
+        if h & m == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Chunking, Codec, Stored};
+    use crate::crypto::hash;
+    use crate::remote::ProviderError;
+    use crate::testing::random_blob;
+
+    #[test]
+    fn cdc_bounds() {
+        let b = random_blob(1 << 20);
+        let chunks = Chunking::cdc(4096).split(&b);
+        let (last, rest) = chunks.split_last().unwrap();
+        assert!(rest.iter().all(|c| 1024 <= c.chunk.len()));
+        assert!(chunks.iter().all(|c| c.chunk.len() <= 4 * 4096));
+        assert!(!last.chunk.is_empty());
+        let avg = b.len() / chunks.len();
+        assert!(2048 < avg && avg < 8192, "average chunk is {}", avg);
+        let joined: Vec<u8> = chunks.iter().flat_map(|c| c.chunk.clone()).collect();
+        assert_eq!(joined, b);
+    }
+
+    #[test]
+    fn cdc_shift_resistance() {
+        let b = random_blob(1 << 18);
+        let mut shifted = vec![42];
+        shifted.extend_from_slice(&b);
+        let chunking = Chunking::cdc(1024);
+        let hashes =
+            |s: &[u8]| -> Vec<_> { chunking.split(s).into_iter().map(|c| c.hash).collect() };
+        let (original, shifted) = (hashes(&b), hashes(&shifted));
+        let common = shifted.iter().filter(|h| original.contains(h)).count();
+        assert!(common + 2 >= original.len());
+    }
+
+    #[test]
+    fn check_received() {
+        let data = random_blob(100);
+        let stored = Stored {
+            hash: hash(&data),
+            name: hash(&data),
+            account: 0,
+            len: data.len(),
+            codec: Codec::None,
+        };
+        assert!(stored.check(&data).is_ok());
+        match stored.check(&data[1..]) {
+            Err(ProviderError::Mismatch(ref h)) if *h == stored.hash => {}
+            r => panic!("Unexpected {:?}", r),
+        }
+        let mut tampered = data.clone();
+        tampered[42] ^= 1;
+        match stored.check(&tampered) {
+            Err(ProviderError::Mismatch(ref h)) if *h == stored.hash => {}
+            r => panic!("Unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn parse_codec() {
+        for c in &[Codec::None, Codec::Zstd] {
+            assert_eq!(c.to_string().parse::<Codec>().as_ref(), Ok(c));
+        }
+        assert!("lzma".parse::<Codec>().is_err());
+    }
+
+    #[test]
+    fn parse_chunking() {
+        for c in &[Chunking::Fixed(512), Chunking::cdc(4096)] {
+            assert_eq!(c.to_string().parse::<Chunking>().as_ref(), Ok(c));
+        }
+        assert!("fixed:0".parse::<Chunking>().is_err());
+        assert!("cdc:8:4:16".parse::<Chunking>().is_err());
+        assert!("cdc:1:1:4".parse::<Chunking>().is_err());
+        assert!("rabin:512".parse::<Chunking>().is_err());
+    }
+}
@@ -1,8 +1,15 @@
 use std::fmt;
 
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 use sha3::{Digest, Sha3_256};
 
 pub const HASH_SIZE: usize = 32;
+pub const KEY_SIZE: usize = 32;
+pub const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 24;
 
 #[derive(Debug, Eq, Clone)]
 pub struct Hash([u8; HASH_SIZE]);
@@ -32,6 +39,12 @@ impl PartialEq for Hash {
     }
 }
 
+impl std::hash::Hash for Hash {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
 impl fmt::Display for Hash {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         self.0
@@ -42,9 +55,99 @@ impl fmt::Display for Hash {
     }
 }
 
+/// Secret key of the authenticated chunk encryption
+#[derive(Clone)]
+pub struct Key([u8; KEY_SIZE]);
+
+impl Key {
+    pub fn new(k: [u8; KEY_SIZE]) -> Key {
+        Key(k)
+    }
+
+    pub fn random() -> Key {
+        Key(rand::random())
+    }
+
+    pub fn key(&self) -> &[u8; KEY_SIZE] {
+        &self.0
+    }
+
+    /// Derives the key from a passphrase with Argon2id
+    pub fn derive(passphrase: &[u8], salt: &[u8; SALT_SIZE]) -> Key {
+        let config = argon2::Config {
+            variant: argon2::Variant::Argon2id,
+            hash_length: KEY_SIZE as u32,
+            ..Default::default()
+        };
+        let raw = argon2::hash_raw(passphrase, salt, &config).expect("Invalid Argon2 parameters");
+        let mut key = [0u8; KEY_SIZE];
+        key.copy_from_slice(&raw);
+        Key(key)
+    }
+
+    /// Encrypts data with XChaCha20-Poly1305, the random nonce is prepended
+    /// to the ciphertext
+    pub fn seal(&self, data: &[u8]) -> Vec<u8> {
+        self.seal_with(rand::random(), data)
+    }
+
+    /// Encrypts data with the key of the chunk with hash `h`, so equal chunks
+    /// give equal ciphertexts, `open` of `chunk_key(h)` decrypts it
+    ///
+    /// The nonce is fixed since the key is never reused for other data.
+    pub fn seal_convergent(&self, h: &Hash, data: &[u8]) -> Vec<u8> {
+        self.chunk_key(h).seal_with([0; NONCE_SIZE], data)
+    }
+
+    /// Key of a single chunk derived from its plaintext hash
+    pub fn chunk_key(&self, h: &Hash) -> Key {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.0).expect("HMAC accepts any key");
+        mac.input(h.hash());
+        let mut key = [0u8; KEY_SIZE];
+        key.copy_from_slice(&mac.result().code());
+        Key(key)
+    }
+
+    /// Name of the remote object holding the chunk with hash `h`
+    pub fn name(&self, h: &Hash) -> Hash {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.0).expect("HMAC accepts any key");
+        mac.input(b"name");
+        mac.input(h.hash());
+        let mut name = [0u8; HASH_SIZE];
+        name.copy_from_slice(&mac.result().code());
+        Hash(name)
+    }
+
+    fn seal_with(&self, nonce: [u8; NONCE_SIZE], data: &[u8]) -> Vec<u8> {
+        let mut sealed = nonce.to_vec();
+        sealed.extend(
+            self.cipher()
+                .encrypt(&XNonce::from(nonce), data)
+                .expect("Chunk is too large to encrypt"),
+        );
+        sealed
+    }
+
+    /// Decrypts data produced by `seal`, `None` if it was modified or
+    /// encrypted with another key
+    pub fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < NONCE_SIZE {
+            return None;
+        }
+        let (n, ciphertext) = sealed.split_at(NONCE_SIZE);
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce.copy_from_slice(n);
+        self.cipher().decrypt(&XNonce::from(nonce), ciphertext).ok()
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&chacha20poly1305::Key::from(self.0))
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::{Hash, HASH_SIZE};
+    use super::{hash, Hash, Key, HASH_SIZE, SALT_SIZE};
     #[test]
     fn test_hash_fmt() {
         let mut a = [0u8; HASH_SIZE];
@@ -57,4 +160,55 @@ mod test {
             "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"
         );
     }
+
+    #[test]
+    fn seal_open() {
+        let salt = [1u8; SALT_SIZE];
+        let key = Key::derive(b"passphrase", &salt);
+        let data = b"some chunk".to_vec();
+        let sealed = key.seal(&data);
+        assert_ne!(&sealed[..], &data[..]);
+        assert_ne!(sealed, key.seal(&data));
+        assert_eq!(key.open(&sealed), Some(data.clone()));
+        assert_eq!(Key::derive(b"passphrase", &salt).open(&sealed), Some(data));
+
+        assert_eq!(Key::derive(b"another", &salt).open(&sealed), None);
+        assert_eq!(
+            Key::derive(b"passphrase", &[2; SALT_SIZE]).open(&sealed),
+            None
+        );
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(key.open(&tampered), None);
+        assert_eq!(key.open(&sealed[..10]), None);
+    }
+
+    #[test]
+    fn seal_convergent() {
+        let key = Key::derive(b"passphrase", &[1; SALT_SIZE]);
+        let data = b"some chunk".to_vec();
+        let h = hash(&data);
+        let sealed = key.seal_convergent(&h, &data);
+        assert_eq!(sealed, key.seal_convergent(&h, &data));
+        assert_eq!(key.chunk_key(&h).open(&sealed), Some(data.clone()));
+        assert_eq!(key.open(&sealed), None);
+
+        let other = Key::derive(b"another", &[1; SALT_SIZE]);
+        assert_ne!(sealed, other.seal_convergent(&h, &data));
+        let h2 = hash(b"another chunk");
+        assert_ne!(sealed, key.seal_convergent(&h2, &data));
+        assert_eq!(key.chunk_key(&h2).open(&sealed), None);
+    }
+
+    #[test]
+    fn keyed_name() {
+        let key = Key::derive(b"passphrase", &[1; SALT_SIZE]);
+        let h = hash(b"some chunk");
+        assert_eq!(key.name(&h), key.name(&h));
+        assert_ne!(key.name(&h), h);
+        assert_ne!(key.name(&h).hash(), &key.chunk_key(&h).0);
+        assert_ne!(key.name(&h), key.name(&hash(b"another chunk")));
+        let other = Key::derive(b"another", &[1; SALT_SIZE]);
+        assert_ne!(key.name(&h), other.name(&h));
+    }
 }
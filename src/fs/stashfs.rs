@@ -1,4 +1,3 @@
-use std::cmp::min;
 use std::path::Path;
 
 use libc;
@@ -6,15 +5,22 @@ use log::*;
 use netfuse::{mount, DirEntry, LibcError, Metadata, MountOptions, NetworkFilesystem};
 use time::Timespec;
 
-use crate::chunk::CHUNK_SIZE;
-use crate::crypto::Hash;
-use crate::local::Db;
-use crate::remote::Provider;
+use crate::chunk::{self, Chunking, Codec};
+use crate::crypto::Key;
+use crate::local::{Db, DbError, Meta};
+use crate::remote::compressed::compress;
+use crate::remote::{Provider, ProviderError};
 use fuse::FileType;
 
 pub struct StashFs<D: Db, P: Provider> {
     db: D,
     provider: P,
+    /// Strategy written files are split into chunks with
+    chunking: Chunking,
+    /// Key remote names of new chunks are derived with
+    naming: Option<Key>,
+    /// Codec written chunks are compressed with where it makes them shorter
+    codec: Codec,
 }
 
 fn get_path(path: &Path) -> Result<&str, LibcError> {
@@ -27,14 +33,71 @@ fn get_path(path: &Path) -> Result<&str, LibcError> {
         .ok_or(libc::ENOENT)
 }
 
+fn provider_errno(e: ProviderError) -> LibcError {
+    warn!("{}", e);
+    match e {
+        ProviderError::Network(_)
+        | ProviderError::Partial(_)
+        | ProviderError::Corrupted(_)
+        | ProviderError::Mismatch(_)
+        | ProviderError::UnknownAccount(_)
+        | ProviderError::Invalid(_) => libc::EIO,
+        ProviderError::Auth => libc::EACCES,
+        ProviderError::QuotaExceeded => libc::ENOSPC,
+        ProviderError::NotFound => libc::ENOENT,
+        ProviderError::RateLimited(_) => libc::EAGAIN,
+    }
+}
+
+fn db_errno(e: DbError) -> LibcError {
+    match e {
+        DbError::NotFound => libc::ENOENT,
+        DbError::Constraint(_) => libc::EEXIST,
+        DbError::Io(_) | DbError::Corrupted(_) => {
+            warn!("{}", e);
+            libc::EIO
+        }
+    }
+}
+
 impl<D: Db, P: Provider> StashFs<D, P> {
-    fn find(&mut self, fname: &str) -> Result<(usize, Vec<Hash>), LibcError> {
-        self.db.find(fname).map_err(|_| libc::ENOENT)
+    fn find(&mut self, fname: &str) -> Result<(Meta, Vec<chunk::Stored>), LibcError> {
+        self.db.find(fname).map_err(db_errno)
+    }
+
+    /// Deletes chunks only the file refers to and then forgets the file, so
+    /// a failed removal can be repeated
+    fn remove(&mut self, fname: &str) -> Result<(), LibcError> {
+        let orphans = self.db.orphans(fname).map_err(db_errno)?;
+        self.provider.delete(&orphans).map_err(provider_errno)?;
+        self.db.clean(fname).map_err(db_errno)?;
+        Ok(())
     }
 
-    pub fn mount_with(d: D, p: P, path: &str) {
+    /// Deletes chunks which are not referred to anymore, a failure leaves
+    /// them on the provider only
+    fn delete_unused(&mut self, fname: &str, unused: &[chunk::Stored]) {
+        if let Err(e) = self.provider.delete(unused) {
+            warn!("{}: can't delete unused chunks: {}", fname, e);
+        }
+    }
+
+    pub fn mount_with(
+        d: D,
+        p: P,
+        chunking: Chunking,
+        naming: Option<Key>,
+        codec: Codec,
+        path: &str,
+    ) {
         mount(
-            StashFs { db: d, provider: p },
+            StashFs {
+                db: d,
+                provider: p,
+                chunking,
+                naming,
+                codec,
+            },
             MountOptions::new(&Path::new(path)),
         )
     }
@@ -43,9 +106,9 @@ impl<D: Db, P: Provider> StashFs<D, P> {
 impl<D: Db, P: Provider> NetworkFilesystem for StashFs<D, P> {
     fn lookup(&mut self, path: &Path) -> Result<Metadata, LibcError> {
         trace!("#lookup {:?}", path);
-        let (fsize, _) = get_path(path).and_then(|p| self.find(p))?;
+        let (meta, _) = get_path(path).and_then(|p| self.find(p))?;
         Ok(Metadata {
-            size: fsize as u64,
+            size: meta.size as u64,
             atime: Timespec::new(0, 0),
             mtime: Timespec::new(0, 0),
             ctime: Timespec::new(0, 0),
@@ -57,40 +120,66 @@ impl<D: Db, P: Provider> NetworkFilesystem for StashFs<D, P> {
 
     fn read(&mut self, path: &Path, buffer: &mut Vec<u8>) -> Result<usize, LibcError> {
         trace!("#read {:?}", path);
-        let (fsize, hlist) = get_path(path).and_then(|p| self.find(p))?;
-        buffer.reserve(fsize);
-        hlist.iter().enumerate().for_each(|(i, h)| {
-            buffer.extend_from_slice(
-                &self.provider.receive(&h)[..min(fsize - i * CHUNK_SIZE, CHUNK_SIZE)],
-            )
-        });
-        Ok(fsize)
+        let (meta, chunks) = get_path(path).and_then(|p| self.find(p))?;
+        let start = buffer.len();
+        buffer.reserve(meta.size);
+        for c in chunks.iter() {
+            let data = self.provider.receive(&c).map_err(provider_errno)?;
+            c.check(&data).map_err(provider_errno)?;
+            buffer.extend_from_slice(&data);
+        }
+        // the last chunk of legacy files is padded
+        buffer.truncate(start + meta.size);
+        Ok(meta.size)
     }
 
     fn write(&mut self, path: &Path, data: &[u8]) -> Result<(), LibcError> {
         trace!("#write {:?}", path);
         let fname = get_path(path)?;
-        // make sure the file exists
-        if let Ok((_, hs)) = self.find(&fname) {
-            // TODO: full cleaning shouldn't be done every time
-            self.db.clean(&fname);
-            self.provider.delete(&hs);
+        // content left by an interrupted write is forgotten
+        match self.db.discard(fname) {
+            Ok(unused) => self.delete_unused(fname, &unused),
+            Err(DbError::NotFound) => {}
+            Err(e) => return Err(db_errno(e)),
+        }
+        // the old content is kept until the new one is published
+        let saved = self
+            .db
+            .save_pending(fname, data, &self.chunking)
+            .map_err(db_errno)?;
+        debug!(
+            "{}: {} new chunks, {} deduplicated",
+            fname,
+            saved.new.len(),
+            saved.deduplicated
+        );
+        let mut chunks = saved.new;
+        if let Some(ref key) = self.naming {
+            chunk::name_with(&mut chunks, key);
         }
-        self.db
-            .save(fname, data)
-            .iter()
-            .for_each(|c| self.provider.publish(c));
+        compress(&mut chunks, self.codec);
+        let accounts = match self.provider.publish_batch(&chunks) {
+            Ok(accounts) => accounts,
+            Err(e) => {
+                // forget the new content, so the old one stays readable
+                if let Ok(unused) = self.db.discard(fname) {
+                    self.delete_unused(fname, &unused);
+                }
+                return Err(provider_errno(e));
+            }
+        };
+        for (c, account) in chunks.iter().zip(accounts) {
+            self.db.place(c, account).map_err(db_errno)?;
+        }
+        let replaced = self.db.commit(fname).map_err(db_errno)?;
+        self.delete_unused(fname, &replaced);
         Ok(())
     }
 
     fn unlink(&mut self, path: &Path) -> Result<(), LibcError> {
         trace!("#unlink {:?}", path);
         let fname = get_path(path)?;
-        let (_, hs) = self.find(&fname)?;
-        self.db.clean(&fname);
-        // TODO: use counter to check whether it should be deleted
-        self.provider.delete(&hs);
-        Ok(())
+        self.remove(fname)
     }
 
     fn readdir(&mut self, path: &Path) -> Vec<Result<DirEntry, LibcError>> {
@@ -98,9 +187,11 @@ impl<D: Db, P: Provider> NetworkFilesystem for StashFs<D, P> {
             Ok(s) => s,
             Err(e) => return vec![Err(e)],
         };
-        self.db
-            .list()
-            .into_iter()
+        let list = match self.db.list() {
+            Ok(list) => list,
+            Err(e) => return vec![Err(db_errno(e))],
+        };
+        list.into_iter()
             .inspect(|(s, meta)| trace!("{:?}", (s, meta)))
             .filter(|(s, _)| s.as_str().starts_with(&begin))
             .map(|(mut s, meta)| {
@@ -125,3 +216,90 @@ impl<D: Db, P: Provider> NetworkFilesystem for StashFs<D, P> {
             .collect()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::Path;
+
+    use netfuse::NetworkFilesystem;
+
+    use super::StashFs;
+    use crate::chunk::{Chunking, Codec};
+    use crate::local::memory::Memory;
+    use crate::local::Db;
+    use crate::remote::directory::Directory;
+    use crate::testing::{random_blob, tmpdir};
+
+    fn write_read_unlink<D: Db>(db: D) {
+        let root = tmpdir();
+        let mut stash = StashFs {
+            db,
+            provider: Directory::new(&root).unwrap(),
+            chunking: Chunking::Fixed(1000),
+            naming: None,
+            codec: Codec::None,
+        };
+        let path = Path::new("/file");
+        let content = random_blob(1500);
+
+        stash.write(path, &random_blob(1500)).unwrap();
+        stash.write(path, &content).unwrap();
+        assert_eq!(stash.lookup(path).unwrap().size, content.len() as u64);
+        let mut buffer = Vec::new();
+        assert_eq!(stash.read(path, &mut buffer).unwrap(), content.len());
+        assert_eq!(buffer, content);
+        // the new content is not listed as another file while it's written
+        assert_eq!(stash.readdir(Path::new("/")).len(), 1);
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 2);
+
+        stash.unlink(path).unwrap();
+        assert!(stash.lookup(path).is_err());
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 0);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn memory_write_read_unlink() {
+        write_read_unlink(Memory::new());
+    }
+
+    #[test]
+    #[cfg(feature = "persistent")]
+    fn sqlite_write_read_unlink() {
+        use crate::local::sqlite::Sqlite;
+        write_read_unlink(Sqlite::new("test_stashfs.db").unwrap());
+        fs::remove_file("test_stashfs.db").unwrap();
+    }
+
+    #[test]
+    fn keep_old_content_on_failure() {
+        let root = tmpdir();
+        let mut stash = StashFs {
+            db: Memory::new(),
+            provider: Directory::new(&root).unwrap(),
+            chunking: Chunking::Fixed(1000),
+            naming: None,
+            codec: Codec::None,
+        };
+        let path = Path::new("/file");
+        let old = random_blob(1500);
+        let new = random_blob(1500);
+        stash.write(path, &old).unwrap();
+
+        let kept = root.with_extension("kept");
+        fs::rename(&root, &kept).unwrap();
+        assert!(stash.write(path, &new).is_err());
+        fs::rename(&kept, &root).unwrap();
+        let mut buffer = Vec::new();
+        stash.read(path, &mut buffer).unwrap();
+        assert_eq!(buffer, old);
+
+        stash.write(path, &new).unwrap();
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 2);
+        buffer.clear();
+        stash.read(path, &mut buffer).unwrap();
+        assert_eq!(buffer, new);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
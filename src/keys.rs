@@ -0,0 +1,171 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::crypto::{Key, KEY_SIZE, SALT_SIZE};
+
+/// Data keys of the stash wrapped with the master key derived from the
+/// passphrase
+///
+/// The file holds the salt of the master key followed by the sealed data keys.
+/// The first data key is the current one, the rest are kept to read chunks
+/// until a rotation is finished, so changing the passphrase rewrites this
+/// file only and chunks are re-encrypted on rotation only.
+pub struct KeyFile {
+    salt: [u8; SALT_SIZE],
+    master: Key,
+    keys: Vec<Key>,
+}
+
+fn invalid(e: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+impl KeyFile {
+    /// Creates the file of a new stash with a random data key
+    pub fn create(passphrase: &[u8]) -> KeyFile {
+        let salt = rand::random();
+        KeyFile {
+            salt,
+            master: Key::derive(passphrase, &salt),
+            keys: vec![Key::random()],
+        }
+    }
+
+    /// Reads the file and unwraps data keys with the passphrase
+    pub fn open<P: AsRef<Path>>(path: P, passphrase: &[u8]) -> io::Result<KeyFile> {
+        let path = path.as_ref();
+        let content = fs::read(path)?;
+        if content.len() < SALT_SIZE {
+            return Err(invalid(format!("{} is truncated", path.display())));
+        }
+        let (s, sealed) = content.split_at(SALT_SIZE);
+        let mut salt = [0u8; SALT_SIZE];
+        salt.copy_from_slice(s);
+        let master = Key::derive(passphrase, &salt);
+        let raw = master.open(sealed).ok_or_else(|| {
+            invalid(format!(
+                "wrong passphrase or {} is corrupted",
+                path.display()
+            ))
+        })?;
+        if raw.is_empty() || raw.len() % KEY_SIZE != 0 {
+            return Err(invalid(format!("{} holds no keys", path.display())));
+        }
+        let keys = raw
+            .chunks(KEY_SIZE)
+            .map(|k| {
+                let mut key = [0u8; KEY_SIZE];
+                key.copy_from_slice(k);
+                Key::new(key)
+            })
+            .collect();
+        Ok(KeyFile { salt, master, keys })
+    }
+
+    /// Opens the file or creates it if the stash has none yet
+    pub fn open_or_create<P: AsRef<Path>>(path: P, passphrase: &[u8]) -> io::Result<KeyFile> {
+        match KeyFile::open(&path, passphrase) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                let keys = KeyFile::create(passphrase);
+                keys.save(path)?;
+                Ok(keys)
+            }
+            r => r,
+        }
+    }
+
+    /// Writes the file, the old one is replaced only once the new one is
+    /// written completely
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let raw: Vec<u8> = self.keys.iter().flat_map(|k| k.key().to_vec()).collect();
+        let mut content = self.salt.to_vec();
+        content.extend(self.master.seal(&raw));
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, &content)?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Data keys, the current one goes first
+    pub fn keys(&self) -> &[Key] {
+        &self.keys
+    }
+
+    /// Wraps data keys with a master key of the new passphrase
+    pub fn change_passphrase(&mut self, passphrase: &[u8]) {
+        self.salt = rand::random();
+        self.master = Key::derive(passphrase, &self.salt);
+    }
+
+    /// Keeps an older key to read chunks with, returns whether it was new
+    pub fn add(&mut self, key: Key) -> bool {
+        if self.keys.iter().any(|k| k.key() == key.key()) {
+            return false;
+        }
+        self.keys.push(key);
+        true
+    }
+
+    /// Makes a new random data key the current one, previous keys are kept
+    /// until `finish_rotation`
+    pub fn rotate(&mut self) {
+        self.keys.insert(0, Key::random());
+    }
+
+    /// Forgets all the keys except the current one once every chunk is
+    /// re-encrypted with it
+    pub fn finish_rotation(&mut self) {
+        self.keys.truncate(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io;
+
+    use super::KeyFile;
+    use crate::crypto::{Key, SALT_SIZE};
+
+    #[test]
+    fn wrap_and_rotate() {
+        let path = std::env::temp_dir().join(format!("cloud-stash-{}", rand::random::<u64>()));
+        let mut keys = KeyFile::open_or_create(&path, b"passphrase").unwrap();
+        let data = keys.keys()[0].key().to_vec();
+        match KeyFile::open(&path, b"another") {
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {}
+            r => panic!("Unexpected {:?}", r.map(|_| ())),
+        }
+
+        keys.change_passphrase(b"another");
+        keys.save(&path).unwrap();
+        assert!(KeyFile::open(&path, b"passphrase").is_err());
+        let mut keys = KeyFile::open_or_create(&path, b"another").unwrap();
+        assert_eq!(keys.keys().len(), 1);
+        assert_eq!(keys.keys()[0].key().to_vec(), data);
+
+        keys.rotate();
+        keys.save(&path).unwrap();
+        let mut keys = KeyFile::open(&path, b"another").unwrap();
+        assert_eq!(keys.keys().len(), 2);
+        assert_ne!(keys.keys()[0].key().to_vec(), data);
+        assert_eq!(keys.keys()[1].key().to_vec(), data);
+        keys.finish_rotation();
+        keys.save(&path).unwrap();
+        let keys = KeyFile::open(&path, b"another").unwrap();
+        assert_eq!(keys.keys().len(), 1);
+        assert_ne!(keys.keys()[0].key().to_vec(), data);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn add_older_key() {
+        let mut keys = KeyFile::create(b"passphrase");
+        let legacy = Key::derive(b"passphrase", &[0; SALT_SIZE]);
+        assert!(keys.add(legacy.clone()));
+        assert!(!keys.add(legacy.clone()));
+        assert_eq!(keys.keys().len(), 2);
+        assert_eq!(keys.keys()[1].key(), legacy.key());
+    }
+}
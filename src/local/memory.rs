@@ -1,67 +1,202 @@
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 
-use crate::chunk;
-use crate::crypto::{hash, Hash};
-use crate::local::{Db, ErrorFind};
+use crate::chunk::{self, Codec};
+use crate::crypto::Hash;
+use crate::local::{Db, DbError, Meta, Saved};
+use crate::remote::AccountId;
 
 #[derive(Default)]
 pub struct FileInfo {
-    hashes: Vec<Hash>,
-    size: usize,
+    // [Hash, length] of every chunk
+    chunks: Vec<(Hash, usize)>,
+    meta: Meta,
 }
 
 pub struct Memory {
     // fname -> [Hash, offset]
     map: HashMap<String, FileInfo>,
+    // fname -> new content until it's committed
+    pending: HashMap<String, FileInfo>,
+    // Hash -> [account, name, codec]
+    locations: HashMap<Hash, (AccountId, Hash, Codec)>,
+    // Hash -> number of references from files
+    refs: HashMap<Hash, usize>,
 }
 
 impl Memory {
     pub fn new() -> Self {
         Memory {
             map: Default::default(),
+            pending: Default::default(),
+            locations: Default::default(),
+            refs: Default::default(),
         }
     }
+
+    /// Splits content into chunks and counts references to them
+    fn record(&mut self, s: &[u8], chunking: &chunk::Chunking) -> (FileInfo, Saved) {
+        let chunks = chunking.split(s);
+        let info = FileInfo {
+            chunks: chunks
+                .iter()
+                .map(|c| (c.hash.clone(), c.chunk.len()))
+                .collect(),
+            meta: Meta {
+                size: s.len(),
+                chunking: chunking.clone(),
+            },
+        };
+        let mut saved = Saved::default();
+        for c in chunks {
+            *self.refs.entry(c.hash.clone()).or_insert(0) += 1;
+            // chunks which failed to be published are not placed
+            if !self.locations.contains_key(&c.hash) && saved.new.iter().all(|n| n.hash != c.hash) {
+                saved.new.push(c);
+            } else {
+                saved.deduplicated += 1;
+            }
+        }
+        (info, saved)
+    }
+
+    /// Drops references of the content and returns chunks left without any
+    fn release(&mut self, info: FileInfo) -> Vec<chunk::Stored> {
+        let mut orphans = Vec::new();
+        for (h, len) in info.chunks {
+            if let Entry::Occupied(mut e) = self.refs.entry(h) {
+                *e.get_mut() -= 1;
+                if *e.get() == 0 {
+                    let (hash, _) = e.remove_entry();
+                    let (account, name, codec) =
+                        self.locations
+                            .remove(&hash)
+                            .unwrap_or((0, hash.clone(), Codec::None));
+                    orphans.push(chunk::Stored {
+                        hash,
+                        name,
+                        account,
+                        len,
+                        codec,
+                    });
+                }
+            }
+        }
+        orphans
+    }
 }
 
 impl Db for Memory {
-    fn save(&mut self, fname: &str, s: &[u8]) -> chunk::Chunks {
-        let v = self
-            .map
-            .entry(fname.to_string())
-            .or_insert_with(|| Default::default());
-        v.size = s.len();
-        s.chunks(chunk::CHUNK_SIZE)
-            .enumerate()
-            .map(|(idx, c)| {
-                let mut block = [0u8; chunk::CHUNK_SIZE];
-                c.iter().enumerate().for_each(|(i, c)| block[i] = *c);
-                let block = block;
-                let h = hash(&block);
-                v.hashes.push(h.clone());
-                chunk::Chunk {
-                    hash: h,
-                    chunk: block,
-                    idx: idx as u64,
-                }
-            })
-            .collect()
+    fn save(
+        &mut self,
+        fname: &str,
+        s: &[u8],
+        chunking: &chunk::Chunking,
+    ) -> Result<Saved, DbError> {
+        if self.map.contains_key(fname) {
+            return Err(DbError::Constraint(format!("{} already exists", fname)));
+        }
+        let (info, saved) = self.record(s, chunking);
+        self.map.insert(fname.to_string(), info);
+        Ok(saved)
     }
 
-    fn find(&mut self, fname: &str) -> Result<(usize, Vec<Hash>), ErrorFind> {
+    fn find(&mut self, fname: &str) -> Result<(Meta, Vec<chunk::Stored>), DbError> {
+        let locations = &self.locations;
         self.map
             .get(fname)
-            .map(|FileInfo { hashes, size }| (*size, hashes.clone()))
-            .ok_or(ErrorFind::NoMatch)
+            .map(|FileInfo { chunks, meta }| {
+                let stored = chunks
+                    .iter()
+                    .map(|(h, len)| {
+                        let (account, name, codec) =
+                            locations
+                                .get(h)
+                                .cloned()
+                                .unwrap_or((0, h.clone(), Codec::None));
+                        chunk::Stored {
+                            hash: h.clone(),
+                            name,
+                            account,
+                            len: *len,
+                            codec,
+                        }
+                    })
+                    .collect();
+                (meta.clone(), stored)
+            })
+            .ok_or(DbError::NotFound)
     }
 
-    fn clean(&mut self, fname: &str) {
-        if let Some(_) = self.map.remove(fname) {}
+    fn place(&mut self, c: &chunk::Chunk, account: AccountId) -> Result<(), DbError> {
+        self.locations
+            .insert(c.hash.clone(), (account, c.name.clone(), c.codec));
+        Ok(())
     }
 
-    fn list(&mut self) -> Vec<(String, usize)> {
-        self.map
+    fn orphans(&mut self, fname: &str) -> Result<Vec<chunk::Stored>, DbError> {
+        let info = self.map.get(fname).ok_or(DbError::NotFound)?;
+        let mut own: HashMap<&Hash, usize> = HashMap::new();
+        info.chunks
+            .iter()
+            .for_each(|(h, _)| *own.entry(h).or_insert(0) += 1);
+        let mut orphans: Vec<chunk::Stored> = Vec::new();
+        for (h, len) in &info.chunks {
+            if self.refs.get(h) == own.get(h) && orphans.iter().all(|o| o.hash != *h) {
+                let (account, name, codec) =
+                    self.locations
+                        .get(h)
+                        .cloned()
+                        .unwrap_or((0, h.clone(), Codec::None));
+                orphans.push(chunk::Stored {
+                    hash: h.clone(),
+                    name,
+                    account,
+                    len: *len,
+                    codec,
+                });
+            }
+        }
+        Ok(orphans)
+    }
+
+    fn clean(&mut self, fname: &str) -> Result<Vec<chunk::Stored>, DbError> {
+        let info = self.map.remove(fname).ok_or(DbError::NotFound)?;
+        Ok(self.release(info))
+    }
+
+    fn save_pending(
+        &mut self,
+        fname: &str,
+        s: &[u8],
+        chunking: &chunk::Chunking,
+    ) -> Result<Saved, DbError> {
+        if self.pending.contains_key(fname) {
+            return Err(DbError::Constraint(format!("{} is being written", fname)));
+        }
+        let (info, saved) = self.record(s, chunking);
+        self.pending.insert(fname.to_string(), info);
+        Ok(saved)
+    }
+
+    fn commit(&mut self, fname: &str) -> Result<Vec<chunk::Stored>, DbError> {
+        let info = self.pending.remove(fname).ok_or(DbError::NotFound)?;
+        Ok(match self.map.insert(fname.to_string(), info) {
+            Some(replaced) => self.release(replaced),
+            None => Vec::new(),
+        })
+    }
+
+    fn discard(&mut self, fname: &str) -> Result<Vec<chunk::Stored>, DbError> {
+        let info = self.pending.remove(fname).ok_or(DbError::NotFound)?;
+        Ok(self.release(info))
+    }
+
+    fn list(&mut self) -> Result<Vec<(String, usize)>, DbError> {
+        Ok(self
+            .map
             .iter()
-            .map(|(name, FileInfo { size, .. })| (name.clone(), size.clone()))
-            .collect()
+            .map(|(name, FileInfo { meta, .. })| (name.clone(), meta.size))
+            .collect())
     }
 }
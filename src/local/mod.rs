@@ -1,71 +1,298 @@
+use std::fmt;
+
 use crate::chunk;
-use crate::crypto::Hash;
+use crate::remote::AccountId;
 
 pub mod memory;
 #[cfg(feature = "persistent")]
 pub mod sqlite;
 
 #[derive(Debug)]
-pub enum ErrorFind {
+pub enum DbError {
+    /// Storage can't be read or written
+    Io(String),
+    /// Stored data is malformed
+    Corrupted(String),
+    /// Operation violates a constraint, e.g. file name is already taken
+    Constraint(String),
     /// File cannot be found
-    NoMatch,
+    NotFound,
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DbError::Io(e) => write!(f, "database I/O failure: {}", e),
+            DbError::Corrupted(e) => write!(f, "database is corrupted: {}", e),
+            DbError::Constraint(e) => write!(f, "{}", e),
+            DbError::NotFound => write!(f, "file not found"),
+        }
+    }
+}
+
+/// Properties of a saved file
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct Meta {
+    pub size: usize,
+    /// Strategy the file was split into chunks with
+    pub chunking: chunk::Chunking,
+}
+
+/// Outcome of saving a file
+#[derive(Default)]
+pub struct Saved {
+    /// Chunks which were not stored before, or were never placed as their
+    /// publishing failed, and have to be published
+    pub new: chunk::Chunks,
+    /// Number of chunks already stored for other files or earlier in the same
+    pub deduplicated: usize,
 }
 
 pub trait Db {
-    fn save(&mut self, fname: &str, s: &[u8]) -> chunk::Chunks;
-    // TODO: replace usize with metainfo
-    fn find(&mut self, fname: &str) -> Result<(usize, Vec<Hash>), ErrorFind>;
-    fn clean(&mut self, fname: &str);
-    fn list(&mut self) -> Vec<(String, usize)>;
+    /// Splits file into chunks and records them along with their boundaries
+    fn save(&mut self, fname: &str, s: &[u8], chunking: &chunk::Chunking)
+        -> Result<Saved, DbError>;
+    fn find(&mut self, fname: &str) -> Result<(Meta, Vec<chunk::Stored>), DbError>;
+    /// Records the account and the name chunk was published with, chunks
+    /// without a record are considered to be on the first account under
+    /// their hash
+    fn place(&mut self, c: &chunk::Chunk, account: AccountId) -> Result<(), DbError>;
+    /// Returns chunks of the file no other file refers to, i.e. those `clean`
+    /// would remove, without changing anything, so they can be deleted from
+    /// the provider before the file is forgotten
+    fn orphans(&mut self, fname: &str) -> Result<Vec<chunk::Stored>, DbError>;
+    /// Removes file and returns its chunks no other file refers to, so they
+    /// can be deleted from the provider
+    fn clean(&mut self, fname: &str) -> Result<Vec<chunk::Stored>, DbError>;
+    /// Like `save`, but keeps the new content of the file aside until
+    /// `commit`, so the current one is found until the new one is published
+    fn save_pending(
+        &mut self,
+        fname: &str,
+        s: &[u8],
+        chunking: &chunk::Chunking,
+    ) -> Result<Saved, DbError>;
+    /// Replaces the file with its pending content at once and returns chunks
+    /// of the replaced content nothing else refers to
+    fn commit(&mut self, fname: &str) -> Result<Vec<chunk::Stored>, DbError>;
+    /// Forgets the pending content of the file and returns its chunks
+    /// nothing else refers to
+    fn discard(&mut self, fname: &str) -> Result<Vec<chunk::Stored>, DbError>;
+    fn list(&mut self) -> Result<Vec<(String, usize)>, DbError>;
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::crypto::Hash;
+    use crate::chunk::{Chunking, Codec, Stored};
+    use crate::crypto::{hash, Hash};
 
     fn test_save_and_find<D: Db, F: FnOnce() -> D>(f: F) {
         let mut mem = f();
         let buf = ([1, 2, 3, 4, 5], [0, 0, 0, 0], [6, 5, 4, 3, 2, 1]);
-        mem.save("file1", &buf.0);
-        mem.save("file2", &buf.1);
-        mem.save("file3", &buf.2);
+        mem.save("file1", &buf.0, &Chunking::default()).unwrap();
+        let saved = mem.save("file2", &buf.1, &Chunking::Fixed(2)).unwrap();
+        assert_eq!((saved.new.len(), saved.deduplicated), (1, 1));
+        mem.save("file3", &buf.2, &Chunking::default()).unwrap();
+        let mut compressed = saved.new[0].clone();
+        compressed.codec = Codec::Zstd;
+        mem.place(&compressed, 2).unwrap();
 
+        let zeros = [
+            118, 43, 166, 163, 217, 49, 43, 243, 230, 220, 113, 231, 79, 52, 32, 142, 136, 159,
+            196, 78, 111, 244, 0, 114, 77, 238, 207, 237, 167, 213, 179, 206,
+        ];
+        let first = [
+            184, 171, 70, 154, 238, 174, 12, 197, 167, 138, 236, 171, 149, 254, 154, 74, 48, 32,
+            218, 216, 205, 150, 141, 145, 110, 46, 15, 35, 31, 28, 63, 235,
+        ];
+        let third = [
+            1, 192, 240, 175, 250, 3, 155, 120, 134, 247, 20, 127, 108, 139, 7, 182, 236, 167, 16,
+            252, 18, 87, 198, 138, 184, 66, 208, 218, 25, 203, 65, 113,
+        ];
         let size1 = buf.0.len();
         let size2 = buf.1.len();
         let size3 = buf.2.len();
         assert_eq!(
             mem.find("file1").unwrap(),
             (
-                size1,
-                vec![Hash::new([
-                    138, 239, 195, 139, 37, 51, 206, 224, 1, 48, 66, 167, 28, 54, 154, 4, 182, 89,
-                    6, 101, 37, 91, 120, 227, 245, 217, 14, 16, 169, 20, 236, 73
-                ])]
+                Meta {
+                    size: size1,
+                    chunking: Chunking::default(),
+                },
+                vec![Stored {
+                    hash: Hash::new(first),
+                    name: Hash::new(first),
+                    account: 0,
+                    len: size1,
+                    codec: Codec::None,
+                }]
             )
         );
         assert_eq!(
             mem.find("file2").unwrap(),
             (
-                size2,
-                vec![Hash::new([
-                    98, 45, 225, 225, 86, 141, 222, 243, 108, 75, 137, 183, 6, 176, 82, 1, 193, 52,
-                    129, 195, 87, 93, 15, 200, 4, 255, 130, 36, 120, 127, 203, 89
-                ])]
+                Meta {
+                    size: size2,
+                    chunking: Chunking::Fixed(2),
+                },
+                vec![
+                    Stored {
+                        hash: Hash::new(zeros),
+                        name: Hash::new(zeros),
+                        account: 2,
+                        len: 2,
+                        codec: Codec::Zstd,
+                    };
+                    2
+                ]
             )
         );
         assert_eq!(
             mem.find("file3").unwrap(),
             (
-                size3,
-                vec![Hash::new([
-                    2, 147, 59, 163, 101, 135, 58, 137, 86, 67, 119, 88, 233, 235, 154, 57, 136,
-                    100, 119, 5, 98, 25, 173, 141, 237, 30, 4, 195, 252, 89, 139, 113
-                ])]
+                Meta {
+                    size: size3,
+                    chunking: Chunking::default(),
+                },
+                vec![Stored {
+                    hash: Hash::new(third),
+                    name: Hash::new(third),
+                    account: 0,
+                    len: size3,
+                    codec: Codec::None,
+                }]
             )
         );
     }
 
+    fn test_errors<D: Db, F: FnOnce() -> D>(f: F) {
+        let mut mem = f();
+        mem.save("file", &[1, 2, 3], &Chunking::default()).unwrap();
+        match mem.save("file", &[4, 5, 6], &Chunking::default()) {
+            Err(DbError::Constraint(_)) => {}
+            r => panic!("Unexpected {:?}", r.map(|_| ())),
+        }
+        assert_eq!(mem.find("file").unwrap().0.size, 3);
+        mem.save("empty", &[], &Chunking::default()).unwrap();
+        assert_eq!(
+            mem.find("empty").unwrap(),
+            (
+                Meta {
+                    size: 0,
+                    chunking: Chunking::default(),
+                },
+                Vec::new()
+            )
+        );
+        assert!(mem.orphans("empty").unwrap().is_empty());
+        match mem.find("nofile") {
+            Err(DbError::NotFound) => {}
+            r => panic!("Unexpected {:?}", r),
+        }
+    }
+
+    fn test_refcount<D: Db, F: FnOnce() -> D>(f: F) {
+        let mut mem = f();
+        let chunking = Chunking::Fixed(2);
+        let saved = mem.save("file1", &[1, 2, 3, 4, 1, 2], &chunking).unwrap();
+        for c in &saved.new {
+            mem.place(c, 0).unwrap();
+        }
+        let saved = mem.save("file2", &[3, 4, 5, 6], &chunking).unwrap();
+        assert_eq!((saved.new.len(), saved.deduplicated), (1, 1));
+        let mut renamed = saved.new[0].clone();
+        renamed.name = hash(b"name");
+        mem.place(&renamed, 1).unwrap();
+        mem.save("file3", &[3, 4, 5, 6], &chunking).unwrap();
+
+        let orphans = mem.orphans("file1").unwrap();
+        assert_eq!(mem.clean("file1").unwrap(), orphans);
+        assert_eq!(orphans.len(), 1);
+        assert_eq!(orphans[0].hash, hash(&[1, 2]));
+        assert!(mem.clean("file2").unwrap().is_empty());
+        assert_eq!(mem.orphans("file3").unwrap(), mem.find("file3").unwrap().1);
+        assert_eq!(
+            mem.clean("file3").unwrap(),
+            vec![
+                Stored {
+                    hash: hash(&[3, 4]),
+                    name: hash(&[3, 4]),
+                    account: 0,
+                    len: 2,
+                    codec: Codec::None,
+                },
+                Stored {
+                    hash: hash(&[5, 6]),
+                    name: hash(b"name"),
+                    account: 1,
+                    len: 2,
+                    codec: Codec::None,
+                },
+            ]
+        );
+        match mem.clean("file1") {
+            Err(DbError::NotFound) => {}
+            r => panic!("Unexpected {:?}", r),
+        }
+    }
+
+    fn test_unplaced<D: Db, F: FnOnce() -> D>(f: F) {
+        let mut mem = f();
+        let chunking = Chunking::Fixed(2);
+        let saved = mem.save("file1", &[1, 2, 3, 4, 1, 2], &chunking).unwrap();
+        assert_eq!((saved.new.len(), saved.deduplicated), (2, 1));
+        let saved = mem.save("file2", &[1, 2, 3, 4], &chunking).unwrap();
+        assert_eq!((saved.new.len(), saved.deduplicated), (2, 0));
+        mem.place(&saved.new[0], 0).unwrap();
+        let saved = mem.save("file3", &[1, 2, 3, 4], &chunking).unwrap();
+        assert_eq!((saved.new.len(), saved.deduplicated), (1, 1));
+        assert_eq!(saved.new[0].hash, hash(&[3, 4]));
+    }
+
+    fn test_pending<D: Db, F: FnOnce() -> D>(f: F) {
+        let mut mem = f();
+        let chunking = Chunking::Fixed(2);
+        mem.save("file", &[1, 2, 3, 4], &chunking).unwrap();
+        let found = mem.find("file").unwrap();
+        let saved = mem.save_pending("file", &[3, 4, 5, 6], &chunking).unwrap();
+        assert_eq!((saved.new.len(), saved.deduplicated), (2, 0));
+        match mem.save_pending("file", &[7, 8], &chunking) {
+            Err(DbError::Constraint(_)) => {}
+            r => panic!("Unexpected {:?}", r.map(|_| ())),
+        }
+        // pending content is neither found nor listed
+        assert_eq!(mem.find("file").unwrap(), found);
+        assert_eq!(mem.list().unwrap(), vec![("file".to_owned(), 4)]);
+
+        // chunks the current content refers to are kept
+        let discarded = mem.discard("file").unwrap();
+        assert_eq!(discarded.len(), 1);
+        assert_eq!(discarded[0].hash, hash(&[5, 6]));
+        match mem.discard("file") {
+            Err(DbError::NotFound) => {}
+            r => panic!("Unexpected {:?}", r),
+        }
+        match mem.commit("file") {
+            Err(DbError::NotFound) => {}
+            r => panic!("Unexpected {:?}", r),
+        }
+        assert_eq!(mem.find("file").unwrap(), found);
+
+        mem.save_pending("file", &[3, 4, 5, 6], &chunking).unwrap();
+        let replaced = mem.commit("file").unwrap();
+        assert_eq!(replaced, found.1[..1].to_vec());
+        let (meta, stored) = mem.find("file").unwrap();
+        assert_eq!(meta.size, 4);
+        assert_eq!(stored[0].hash, hash(&[3, 4]));
+        assert_eq!(mem.list().unwrap(), vec![("file".to_owned(), 4)]);
+
+        // a new file is committed as well
+        mem.save_pending("new", &[1, 2], &chunking).unwrap();
+        assert!(mem.commit("new").unwrap().is_empty());
+        assert_eq!(mem.find("new").unwrap().0.size, 2);
+    }
+
     #[test]
     fn test_sqlite_save_and_find() {
         use memory::Memory;
@@ -76,7 +303,63 @@ mod tests {
     #[cfg(feature = "persistent")]
     fn test_memory_save_and_find() {
         use sqlite::Sqlite;
-        test_save_and_find::<Sqlite, _>(|| Sqlite::new("test.db"));
+        test_save_and_find::<Sqlite, _>(|| Sqlite::new("test.db").unwrap());
         std::fs::remove_file("test.db").unwrap();
     }
+
+    #[test]
+    fn test_memory_errors() {
+        use memory::Memory;
+        test_errors::<Memory, _>(Memory::new);
+    }
+
+    #[test]
+    #[cfg(feature = "persistent")]
+    fn test_sqlite_errors() {
+        use sqlite::Sqlite;
+        test_errors::<Sqlite, _>(|| Sqlite::new("test_errors.db").unwrap());
+        std::fs::remove_file("test_errors.db").unwrap();
+    }
+
+    #[test]
+    fn test_memory_refcount() {
+        use memory::Memory;
+        test_refcount::<Memory, _>(Memory::new);
+    }
+
+    #[test]
+    #[cfg(feature = "persistent")]
+    fn test_sqlite_refcount() {
+        use sqlite::Sqlite;
+        test_refcount::<Sqlite, _>(|| Sqlite::new("test_refcount.db").unwrap());
+        std::fs::remove_file("test_refcount.db").unwrap();
+    }
+
+    #[test]
+    fn test_memory_pending() {
+        use memory::Memory;
+        test_pending::<Memory, _>(Memory::new);
+    }
+
+    #[test]
+    #[cfg(feature = "persistent")]
+    fn test_sqlite_pending() {
+        use sqlite::Sqlite;
+        test_pending::<Sqlite, _>(|| Sqlite::new("test_pending.db").unwrap());
+        std::fs::remove_file("test_pending.db").unwrap();
+    }
+
+    #[test]
+    fn test_memory_unplaced() {
+        use memory::Memory;
+        test_unplaced::<Memory, _>(Memory::new);
+    }
+
+    #[test]
+    #[cfg(feature = "persistent")]
+    fn test_sqlite_unplaced() {
+        use sqlite::Sqlite;
+        test_unplaced::<Sqlite, _>(|| Sqlite::new("test_unplaced.db").unwrap());
+        std::fs::remove_file("test_unplaced.db").unwrap();
+    }
 }
@@ -1,132 +1,365 @@
 use rusqlite;
+use rusqlite::ErrorCode;
 
-use crate::chunk;
-use crate::crypto::{hash, Hash, HASH_SIZE};
-use crate::local::{Db, ErrorFind};
+use crate::chunk::{self, Codec};
+use crate::crypto::{Hash, HASH_SIZE};
+use crate::local::{Db, DbError, Meta, Saved};
+use crate::remote::AccountId;
 
 pub struct Sqlite {
     conn: rusqlite::Connection,
 }
 
+impl From<rusqlite::Error> for DbError {
+    fn from(e: rusqlite::Error) -> Self {
+        match e {
+            rusqlite::Error::QueryReturnedNoRows => DbError::NotFound,
+            rusqlite::Error::SqliteFailure(ref f, _) => match f.code {
+                ErrorCode::ConstraintViolation => DbError::Constraint(e.to_string()),
+                ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase => {
+                    DbError::Corrupted(e.to_string())
+                }
+                _ => DbError::Io(e.to_string()),
+            },
+            rusqlite::Error::FromSqlConversionFailure(..)
+            | rusqlite::Error::InvalidColumnType(..) => DbError::Corrupted(e.to_string()),
+            _ => DbError::Io(e.to_string()),
+        }
+    }
+}
+
+fn to_hash(blob: Vec<u8>) -> Result<Hash, DbError> {
+    if blob.len() != HASH_SIZE {
+        return Err(DbError::Corrupted(format!(
+            "hash of {} bytes is stored",
+            blob.len()
+        )));
+    }
+    let mut arr = [0u8; HASH_SIZE];
+    arr.copy_from_slice(&blob);
+    Ok(Hash::new(arr))
+}
+
+/// Chunks published without compression have no codec recorded
+fn to_codec(codec: Option<String>) -> Result<Codec, DbError> {
+    codec.map_or(Ok(Codec::None), |c| c.parse().map_err(DbError::Corrupted))
+}
+
 impl Sqlite {
     /// # Relational schema
     ///
     /// ## Table files
-    /// Maps unique filename to its unique identifier
+    /// Maps unique filename to its unique identifier, size and chunking.
+    /// New content of a file has no filename until it's committed, the
+    /// filename is kept in the unique `pending` column instead.
     ///
     /// ## Table hashes
     /// Maps unique pair of chunk hash and chunks' file id to its positional index in file
+    /// and length
+    ///
+    /// ## Table chunks
+    /// Maps unique chunk hash to the account, the name and the codec it is
+    /// published with and the number of references to it from the hashes
+    /// table
     ///
-    fn init(c: &rusqlite::Connection) {
+    /// # Versions
+    /// Tables are created with the columns of the first release and upgraded
+    /// by `ALTER TABLE`, the version is kept in `PRAGMA user_version`.
+    /// Files of the first release keep their chunks: those are 512 bytes
+    /// blocks, the last one padded with zeros, hashed and published with the
+    /// padding, so they are recorded as `fixed:512` and the padding is cut
+    /// off by the file size on reading.
+    fn init(c: &rusqlite::Connection) -> Result<(), DbError> {
         c.execute_batch(concat!(
             "CREATE TABLE IF NOT EXISTS files (fname TEXT, id INTEGER, fsize INTEGER, PRIMARY KEY(id), CONSTRAINT fname_unique UNIQUE (fname));",
-            "CREATE TABLE IF NOT EXISTS hashes (hash BLOB, id INTEGER, idx INTEGER, FOREIGN KEY(id) REFERENCES files(id), PRIMARY KEY(id, idx));")
-        ).unwrap();
+            "CREATE TABLE IF NOT EXISTS hashes (hash BLOB, id INTEGER, idx INTEGER, FOREIGN KEY(id) REFERENCES files(id), PRIMARY KEY(id, idx));",
+            "CREATE TABLE IF NOT EXISTS chunks (hash BLOB, account INTEGER, PRIMARY KEY(hash));")
+        )?;
+        let version: i64 = c.query_row("PRAGMA user_version", &[], |row| row.get_checked(0))??;
+        if version < 1 {
+            Sqlite::add_column(c, "files", "chunking", "TEXT")?;
+            Sqlite::add_column(c, "hashes", "len", "INTEGER")?;
+            Sqlite::add_column(c, "chunks", "refs", "INTEGER")?;
+            Sqlite::add_column(c, "chunks", "name", "BLOB")?;
+            c.execute_batch(concat!(
+                "BEGIN;",
+                "UPDATE files SET chunking='fixed:512' WHERE chunking IS NULL;",
+                "UPDATE hashes SET len=512 WHERE len IS NULL;",
+                "INSERT OR IGNORE INTO chunks (hash) SELECT DISTINCT hash FROM hashes;",
+                "UPDATE chunks SET account=0 WHERE account IS NULL;",
+                "UPDATE chunks SET refs=(SELECT COUNT(*) FROM hashes WHERE hashes.hash=chunks.hash);",
+                "PRAGMA user_version=1;",
+                "COMMIT;")
+            )?;
+        }
+        if version < 2 {
+            Sqlite::add_column(c, "chunks", "codec", "TEXT")?;
+            c.execute_batch("PRAGMA user_version=2;")?;
+        }
+        if version < 3 {
+            Sqlite::add_column(c, "files", "pending", "TEXT")?;
+            c.execute_batch(concat!(
+                "CREATE UNIQUE INDEX IF NOT EXISTS pending_unique ON files (pending);",
+                "PRAGMA user_version=3;"
+            ))?;
+        }
+        Ok(())
     }
 
-    pub fn new(dbfile: &str) -> Sqlite {
-        let c = rusqlite::Connection::open(dbfile).unwrap();
-        Sqlite::init(&c);
-        Sqlite { conn: c }
+    /// Adds the column unless the table already has it
+    fn add_column(
+        c: &rusqlite::Connection,
+        table: &str,
+        column: &str,
+        kind: &str,
+    ) -> Result<(), DbError> {
+        let columns = c
+            .prepare(&format!("PRAGMA table_info({})", table))?
+            .query_map(&[], |row| row.get_checked::<_, String>(1))?
+            .map(|x| x?)
+            .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+        if !columns.iter().any(|name| name == column) {
+            c.execute_batch(&format!(
+                "ALTER TABLE {} ADD COLUMN {} {};",
+                table, column, kind
+            ))?;
+        }
+        Ok(())
+    }
+
+    pub fn new(dbfile: &str) -> Result<Sqlite, DbError> {
+        let c = rusqlite::Connection::open(dbfile)?;
+        Sqlite::init(&c)?;
+        Ok(Sqlite { conn: c })
+    }
+
+    /// Id of the file, or of its new content, by the name in `column`
+    fn id(c: &rusqlite::Connection, column: &str, fname: &str) -> Result<i64, DbError> {
+        let id = c.query_row(
+            &format!("SELECT id FROM files WHERE {}=?", column),
+            &[&fname],
+            |row| row.get_checked(0),
+        )??;
+        Ok(id)
+    }
+
+    /// Records content under the name in `column` like `save`
+    fn insert(
+        &mut self,
+        column: &str,
+        fname: &str,
+        s: &[u8],
+        chunking: &chunk::Chunking,
+    ) -> Result<Saved, DbError> {
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            &format!(
+                "INSERT INTO files ({}, fsize, chunking) VALUES(?, ?, ?)",
+                column
+            ),
+            &[&fname, &(s.len() as i64), &chunking.to_string()],
+        )?;
+        let id = Sqlite::id(&tx, column, fname)?;
+        let mut saved = Saved::default();
+        for c in chunking.split(s) {
+            tx.execute(
+                "INSERT INTO hashes (hash, id, idx, len) VALUES(?, ?, ?, ?)",
+                &[
+                    &c.hash.hash().to_vec(),
+                    &id,
+                    &(c.idx as i64),
+                    &(c.chunk.len() as i64),
+                ],
+            )?;
+            tx.execute(
+                "INSERT OR IGNORE INTO chunks (hash, refs) VALUES(?, 0)",
+                &[&c.hash.hash().to_vec()],
+            )?;
+            tx.execute(
+                "UPDATE chunks SET refs=refs+1 WHERE hash=?",
+                &[&c.hash.hash().to_vec()],
+            )?;
+            // chunks which failed to be published are not placed
+            let placed: bool = tx.query_row(
+                "SELECT account IS NOT NULL FROM chunks WHERE hash=?",
+                &[&c.hash.hash().to_vec()],
+                |row| row.get_checked(0),
+            )??;
+            if !placed && saved.new.iter().all(|n| n.hash != c.hash) {
+                saved.new.push(c);
+            } else {
+                saved.deduplicated += 1;
+            }
+        }
+        tx.commit()?;
+        Ok(saved)
+    }
+
+    /// Removes the content of the id and returns its chunks nothing else
+    /// refers to
+    fn release(c: &rusqlite::Connection, id: i64) -> Result<Vec<chunk::Stored>, DbError> {
+        c.execute(
+            "UPDATE chunks SET refs=refs-(SELECT COUNT(*) FROM hashes WHERE hashes.hash=chunks.hash AND hashes.id=?) WHERE hash IN (SELECT hash FROM hashes WHERE id=?)",
+            &[&id, &id],
+        )?;
+        let orphans = c
+            .prepare(
+                "SELECT hashes.hash, account, len, COALESCE(name, hashes.hash), codec FROM hashes JOIN chunks ON hashes.hash=chunks.hash WHERE hashes.id=? AND refs<=0 GROUP BY hashes.hash ORDER BY MIN(idx)",
+            )?
+            .query_map(&[&id], |row| {
+                Ok(chunk::Stored {
+                    hash: to_hash(row.get_checked(0)?)?,
+                    name: to_hash(row.get_checked(3)?)?,
+                    account: row.get_checked::<_, Option<i64>>(1)?.unwrap_or(0) as AccountId,
+                    len: row.get_checked::<_, i64>(2)? as usize,
+                    codec: to_codec(row.get_checked(4)?)?,
+                })
+            })?
+            .map(|x| x?)
+            .collect::<Result<Vec<_>, DbError>>()?;
+        c.execute("DELETE FROM chunks WHERE refs<=0", &[])?;
+        c.execute("DELETE FROM hashes WHERE id=?", &[&id])?;
+        c.execute("DELETE FROM files WHERE id=?", &[&id])?;
+        Ok(orphans)
     }
 }
 
 impl Db for Sqlite {
-    fn save(&mut self, fname: &str, s: &[u8]) -> chunk::Chunks {
-        self.conn
-            .execute(
-                "INSERT INTO files VALUES(?, NULL, ?)",
-                &[&fname, &(s.len() as i64)],
-            )
-            .unwrap();
-        let id: i64 = self
-            .conn
-            .query_row("SELECT id FROM files WHERE fname=?", &[&fname], |row| {
-                row.get(0)
-            })
-            .unwrap();
-        s.chunks(chunk::CHUNK_SIZE)
-            .enumerate()
-            .map(|(idx, c)| {
-                let mut block = [0u8; chunk::CHUNK_SIZE];
-                c.iter().enumerate().for_each(|(i, c)| block[i] = *c);
-                let block = block;
-                let h = hash(&block);
-                self.conn
-                    .execute(
-                        "INSERT INTO hashes VALUES(?, ?, ?)",
-                        &[
-                            &h.hash().into_iter().cloned().collect::<Vec<u8>>(),
-                            &id,
-                            &(idx as i64),
-                        ],
-                    )
-                    .unwrap();
-                chunk::Chunk {
-                    hash: h,
-                    chunk: block,
-                    idx: idx as u64,
-                }
-            })
-            .collect()
+    fn save(
+        &mut self,
+        fname: &str,
+        s: &[u8],
+        chunking: &chunk::Chunking,
+    ) -> Result<Saved, DbError> {
+        self.insert("fname", fname, s, chunking)
     }
 
-    fn find(&mut self, fname: &str) -> Result<(usize, Vec<Hash>), ErrorFind> {
+    fn find(&mut self, fname: &str) -> Result<(Meta, Vec<chunk::Stored>), DbError> {
+        // an empty file has no chunks, so the file itself is looked up first
+        let meta = self.conn.query_row(
+            "SELECT fsize, chunking FROM files WHERE fname=?",
+            &[&fname],
+            |row| -> Result<_, DbError> {
+                Ok(Meta {
+                    size: row.get_checked::<_, i64>(0)? as usize,
+                    chunking: row
+                        .get_checked::<_, String>(1)?
+                        .parse()
+                        .map_err(DbError::Corrupted)?,
+                })
+            },
+        )??;
         let mut file_info = self.conn
             .prepare(
-                "SELECT hash, idx FROM hashes WHERE hashes.id=(SELECT id FROM files WHERE fname=?) ORDER BY idx",
-            )
-            .unwrap();
-        let vec: Vec<Hash> = file_info
+                "SELECT hashes.hash, idx, account, len, COALESCE(name, hashes.hash), codec FROM hashes LEFT JOIN chunks ON hashes.hash=chunks.hash WHERE hashes.id=(SELECT id FROM files WHERE fname=?) ORDER BY idx",
+            )?;
+        let vec = file_info
             .query_map(&[&fname], |row| {
-                let mut arr = [0u8; HASH_SIZE];
-                row.get::<_, Vec<u8>>(0)
-                    .into_iter()
-                    .enumerate()
-                    .for_each(|(i, x)| arr[i] = x);
-                arr
-            })
-            .unwrap()
-            .map(|x| Hash::new(x.unwrap()))
-            .collect();
-        if vec.is_empty() {
-            Err(ErrorFind::NoMatch)
-        } else {
-            let file_size: i64 = self
-                .conn
-                .query_row("SELECT fsize FROM files WHERE fname=?", &[&fname], |row| {
-                    row.get(0)
+                Ok(chunk::Stored {
+                    hash: to_hash(row.get_checked(0)?)?,
+                    name: to_hash(row.get_checked(4)?)?,
+                    account: row.get_checked::<_, Option<i64>>(2)?.unwrap_or(0) as AccountId,
+                    len: row.get_checked::<_, i64>(3)? as usize,
+                    codec: to_codec(row.get_checked(5)?)?,
                 })
-                .unwrap();
-            Ok((file_size as usize, vec))
-        }
+            })?
+            .map(|x| x?)
+            .collect::<Result<Vec<_>, DbError>>()?;
+        Ok((meta, vec))
     }
 
-    fn clean(&mut self, fname: &str) {
-        self.conn
-            .execute(
-                "DELETE FROM hashes WHERE hashes.id=(SELECT id FROM files WHERE fname=?)",
-                &[&fname],
-            )
-            .unwrap();
-        self.conn
-            .execute("DELETE FROM files WHERE fname=?", &[&fname])
-            .unwrap();
+    fn place(&mut self, c: &chunk::Chunk, account: AccountId) -> Result<(), DbError> {
+        let codec = match c.codec {
+            Codec::None => None,
+            codec => Some(codec.to_string()),
+        };
+        self.conn.execute(
+            "UPDATE chunks SET account=?, name=?, codec=? WHERE hash=?",
+            &[
+                &i64::from(account),
+                &c.name.hash().to_vec(),
+                &codec,
+                &c.hash.hash().to_vec(),
+            ],
+        )?;
+        Ok(())
     }
 
-    fn list(&mut self) -> Vec<(String, usize)> {
+    fn orphans(&mut self, fname: &str) -> Result<Vec<chunk::Stored>, DbError> {
+        let id = Sqlite::id(&self.conn, "fname", fname)?;
+        let mut orphans = self.conn.prepare(
+            "SELECT hashes.hash, account, len, COALESCE(name, hashes.hash), codec FROM hashes JOIN chunks ON hashes.hash=chunks.hash WHERE hashes.id=? GROUP BY hashes.hash HAVING refs<=COUNT(*) ORDER BY MIN(idx)",
+        )?;
+        let orphans = orphans
+            .query_map(&[&id], |row| {
+                Ok(chunk::Stored {
+                    hash: to_hash(row.get_checked(0)?)?,
+                    name: to_hash(row.get_checked(3)?)?,
+                    account: row.get_checked::<_, Option<i64>>(1)?.unwrap_or(0) as AccountId,
+                    len: row.get_checked::<_, i64>(2)? as usize,
+                    codec: to_codec(row.get_checked(4)?)?,
+                })
+            })?
+            .map(|x| x?)
+            .collect::<Result<Vec<_>, DbError>>()?;
+        Ok(orphans)
+    }
+
+    fn clean(&mut self, fname: &str) -> Result<Vec<chunk::Stored>, DbError> {
+        let tx = self.conn.transaction()?;
+        let id = Sqlite::id(&tx, "fname", fname)?;
+        let orphans = Sqlite::release(&tx, id)?;
+        tx.commit()?;
+        Ok(orphans)
+    }
+
+    fn save_pending(
+        &mut self,
+        fname: &str,
+        s: &[u8],
+        chunking: &chunk::Chunking,
+    ) -> Result<Saved, DbError> {
+        self.insert("pending", fname, s, chunking)
+    }
+
+    fn commit(&mut self, fname: &str) -> Result<Vec<chunk::Stored>, DbError> {
+        let tx = self.conn.transaction()?;
+        let id = Sqlite::id(&tx, "pending", fname)?;
+        let orphans = match Sqlite::id(&tx, "fname", fname) {
+            Ok(replaced) => Sqlite::release(&tx, replaced)?,
+            Err(DbError::NotFound) => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        tx.execute(
+            "UPDATE files SET fname=pending, pending=NULL WHERE id=?",
+            &[&id],
+        )?;
+        tx.commit()?;
+        Ok(orphans)
+    }
+
+    fn discard(&mut self, fname: &str) -> Result<Vec<chunk::Stored>, DbError> {
+        let tx = self.conn.transaction()?;
+        let id = Sqlite::id(&tx, "pending", fname)?;
+        let orphans = Sqlite::release(&tx, id)?;
+        tx.commit()?;
+        Ok(orphans)
+    }
+
+    fn list(&mut self) -> Result<Vec<(String, usize)>, DbError> {
         let mut elems = self
             .conn
-            .prepare("SELECT fname, fsize FROM files ORDER BY fname")
-            .unwrap();
-        let elems: Vec<_> = elems
+            .prepare("SELECT fname, fsize FROM files WHERE fname IS NOT NULL ORDER BY fname")?;
+        let elems = elems
             .query_map(&[], |row| {
-                (row.get::<_, String>(0), row.get::<_, i64>(1) as usize)
-            })
-            .unwrap()
-            .map(|x| x.unwrap())
-            .collect();
-        elems
+                Ok((
+                    row.get_checked::<_, String>(0)?,
+                    row.get_checked::<_, i64>(1)? as usize,
+                ))
+            })?
+            .map(|x| x?)
+            .collect::<Result<Vec<_>, DbError>>()?;
+        Ok(elems)
     }
 }
 
@@ -136,22 +369,22 @@ mod test {
     use crate::crypto;
     use crate::local::sqlite::Sqlite;
     use crate::local::Db;
+    use crate::testing::random_blob;
 
     fn init() -> Sqlite {
         use rusqlite::Connection;
         let c = Connection::open_in_memory().unwrap();
-        Sqlite::init(&c);
+        Sqlite::init(&c).unwrap();
         Sqlite { conn: c }
     }
 
-    fn random_blob(sz: usize) -> Vec<u8> {
-        (0..sz).map(|_| rand::random()).collect()
-    }
-
     #[test]
     fn save_chunks_properly() {
         let b = random_blob(chunk::CHUNK_SIZE * 3 + 1);
-        let chunks = init().save("myfile", &b);
+        let chunks = init()
+            .save("myfile", &b, &chunk::Chunking::default())
+            .unwrap()
+            .new;
         assert_eq!(chunks.len(), 4);
         for i in 0..4 {
             assert_eq!(chunks[i].idx, i as u64);
@@ -169,31 +402,52 @@ mod test {
         let mut s = init();
         let b = random_blob(chunk::CHUNK_SIZE * 3 + 1);
         let fname = "myfile";
-        let chunks = s.save(fname, &b);
-        let (bsize, hashes) = s.find(fname).unwrap();
-        assert_eq!(bsize, b.len());
+        let chunks = s.save(fname, &b, &chunk::Chunking::default()).unwrap().new;
+        let (meta, stored) = s.find(fname).unwrap();
+        assert_eq!(meta.size, b.len());
+        assert_eq!(meta.chunking, chunk::Chunking::default());
+        chunks.iter().zip(stored).for_each(|(c, s)| {
+            assert_eq!(c.hash, s.hash);
+            assert_eq!(c.chunk.len(), s.len);
+        });
+    }
+
+    #[test]
+    fn place_chunks() {
+        let mut s = init();
+        let b = random_blob(chunk::CHUNK_SIZE * 3 + 1);
+        let fname = "myfile";
+        let chunks = s.save(fname, &b, &chunk::Chunking::default()).unwrap().new;
         chunks
             .iter()
-            .map(|c| &c.hash)
-            .zip(hashes)
-            .for_each(|(c, h)| assert_eq!(c, &h));
+            .enumerate()
+            .for_each(|(i, c)| s.place(c, i as u32).unwrap());
+        let (_, stored) = s.find(fname).unwrap();
+        assert_eq!(stored.len(), chunks.len());
+        stored
+            .iter()
+            .enumerate()
+            .for_each(|(i, c)| assert_eq!(c.account, i as u32));
     }
 
     #[test]
     fn save_same_chunks() {
         let b = random_blob(chunk::CHUNK_SIZE * 3 + 1);
         let mut s = init();
-        let first_chunks = s.save("myfile1", &b);
-        let second_chunks = s.save("myfile2", &b);
-        first_chunks
+        let first = s.save("myfile1", &b, &chunk::Chunking::default()).unwrap();
+        first.new.iter().for_each(|c| s.place(c, 0).unwrap());
+        let second = s.save("myfile2", &b, &chunk::Chunking::default()).unwrap();
+        first
+            .new
             .iter()
             .flat_map(|c| c.chunk.iter())
-            .zip(second_chunks.iter().flat_map(|c| c.chunk.iter()))
             .zip(&b)
-            .for_each(|((c1, c2), b)| {
-                assert_eq!(c1, b);
-                assert_eq!(c2, b);
-            });
+            .for_each(|(c, b)| assert_eq!(c, b));
+        assert_eq!(first.deduplicated, 0);
+        assert!(second.new.is_empty());
+        assert_eq!(second.deduplicated, first.new.len());
+        let (first, second) = (s.find("myfile1").unwrap(), s.find("myfile2").unwrap());
+        assert_eq!(first, second);
     }
 
     #[test]
@@ -201,19 +455,66 @@ mod test {
         let mut s = init();
         let b = random_blob(chunk::CHUNK_SIZE * 3 + 1);
         let fname = "myfile";
-        let _ = s.save(fname, &b);
+        let _ = s.save(fname, &b, &chunk::Chunking::default()).unwrap();
         assert!(s.find(fname).is_ok());
-        s.clean(fname);
+        s.clean(fname).unwrap();
         assert!(s.find(fname).is_err());
     }
 
+    #[test]
+    fn migrate_legacy() {
+        use rusqlite::Connection;
+        let c = Connection::open_in_memory().unwrap();
+        c.execute_batch(concat!(
+            "CREATE TABLE files (fname TEXT, id INTEGER, fsize INTEGER, PRIMARY KEY(id), CONSTRAINT fname_unique UNIQUE (fname));",
+            "CREATE TABLE hashes (hash BLOB, id INTEGER, idx INTEGER, FOREIGN KEY(id) REFERENCES files(id), PRIMARY KEY(id, idx));",
+            "INSERT INTO files VALUES('legacy', NULL, 600);")
+        )
+        .unwrap();
+        let blocks = [[1u8; chunk::CHUNK_SIZE], [2u8; chunk::CHUNK_SIZE]];
+        for (i, b) in blocks.iter().enumerate() {
+            c.execute(
+                "INSERT INTO hashes VALUES(?, 1, ?)",
+                &[&crypto::hash(b).hash().to_vec(), &(i as i64)],
+            )
+            .unwrap();
+        }
+        Sqlite::init(&c).unwrap();
+        Sqlite::init(&c).unwrap();
+        let mut s = Sqlite { conn: c };
+
+        let (meta, stored) = s.find("legacy").unwrap();
+        assert_eq!(meta.size, 600);
+        assert_eq!(meta.chunking, chunk::Chunking::Fixed(chunk::CHUNK_SIZE));
+        assert_eq!(stored.len(), 2);
+        assert!(stored
+            .iter()
+            .all(|c| c.len == chunk::CHUNK_SIZE && c.name == c.hash));
+        let saved = s.save("new", b"new", &chunk::Chunking::default()).unwrap();
+        assert_eq!(saved.new.len(), 1);
+        // legacy chunks are published already
+        let again = s
+            .save(
+                "again",
+                &blocks.concat(),
+                &chunk::Chunking::Fixed(chunk::CHUNK_SIZE),
+            )
+            .unwrap();
+        assert!(again.new.is_empty());
+        assert_eq!(again.deduplicated, 2);
+        s.clean("again").unwrap();
+        assert_eq!(s.clean("legacy").unwrap(), stored);
+    }
+
     #[test]
     fn save_list() {
         let mut s = init();
         let g = || random_blob(chunk::CHUNK_SIZE);
         let f = ["f1", "f2", "f3"];
-        let _ = (s.save(f[0], &g()), s.save(f[2], &g()), s.save(f[1], &g()));
-        let list = s.list();
+        [f[0], f[2], f[1]]
+            .iter()
+            .for_each(|f| drop(s.save(f, &g(), &chunk::Chunking::default()).unwrap()));
+        let list = s.list().unwrap();
         assert_eq!(list.len(), 3);
         list.iter()
             .enumerate()
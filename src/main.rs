@@ -1,30 +1,52 @@
+use std::cmp::max;
+use std::env;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::process::exit;
+
 use serde_derive::Deserialize;
 
-use crate::local::Db;
+use crate::crypto::Key;
+use crate::keys::KeyFile;
+use crate::local::{Db, DbError};
+use crate::remote::{Provider, ProviderError};
+use crate::service::ServiceError;
 
 mod chunk;
 mod crypto;
 mod fs;
 mod get_token;
+mod keys;
 mod local;
 mod remote;
 mod service;
+#[cfg(test)]
+mod testing;
 
 const USAGE: &str = "
 cloud-stash is a tool for managing multiple file storage accounts.
 Usage:
   cloud-stash (-a | --auth)
-  cloud-stash (-u | --upload) <file> <newname> <token>
-  cloud-stash (-d | --download) <file> <newname> <token>
-  cloud-stash (-r | --remove) <file> <token>
-  cloud-stash (-m | --mount) <file> <token>
+  cloud-stash (-u | --upload) [options] <file> <newname> <token>...
+  cloud-stash (-d | --download) [options] <file> <newname> <token>...
+  cloud-stash (-r | --remove) [options] <file> <token>...
+  cloud-stash (-m | --mount) [options] <file> <token>...
+  cloud-stash --rotate-key [options] <token>...
+  cloud-stash --change-passphrase
   cloud-stash (-h | --help)
   cloud-stash --version
 
 Arguments:
   <file>            File path for working with
   <newname>         New name of the uploaded/saved file
-  <token>           Dropbox auth token
+  <token>           Dropbox auth token, dir:<path> for a local directory,
+                    s3:<endpoint>/<bucket> for S3 compatible storage,
+                    dav:<url> for a WebDAV collection,
+                    sftp://<user>@<host>/<dir> for a directory on SSH host,
+                    gdrive:<token> for a Google Drive account,
+                    onedrive:<token> for a OneDrive account,
+                    chunks are spread across all given accounts,
+                    which are told apart by these strings
 
 Options:
   -a --auth                Authorize app and get a token
@@ -32,30 +54,149 @@ Options:
   -d --download            Download a file
   -r --remove              File removing from the remote host
   -m --mount               Perform fs mount
+  --rotate-key             Re-encrypt stored chunks with a new key
+  --change-passphrase      Wrap keys with a new passphrase, chunks are kept
+  --retries=<n>            Attempts of every chunk transfer [default: 5]
+  --jobs=<n>               Chunk transfers run concurrently [default: 4]
+  --chunk-size=<bytes>     Size of chunks new files are split into [default: 512]
+  --cdc                    Split new files at content-defined boundaries,
+                           chunk size is the average one then, at least 64
+  --convergent             Encrypt equal chunks to equal ciphertexts
+  --compress=<codec>       Compress new chunks with none or zstd [default: none]
   -h --help                Show this help.
   --version                Show version.
+
+Exit status:
+  0  Success
+  1  Invalid arguments
+  2  Local file can't be read or written
+  3  File is not found in the stash
+  4  Local database failure
+  5  Provider rejected the credentials
+  6  Provider storage quota exceeded
+  7  Provider failure
+
+Environment:
+  CLOUD_STASH_PASSPHRASE       Encrypt chunks with keys wrapped with it, the keys
+                               are kept in the keys file next to the db
+  CLOUD_STASH_NEW_PASSPHRASE   Passphrase set by --change-passphrase
 ";
 
+/// Variable holding the passphrase the stash keys are wrapped with
+const PASSPHRASE_VAR: &str = "CLOUD_STASH_PASSPHRASE";
+const NEW_PASSPHRASE_VAR: &str = "CLOUD_STASH_NEW_PASSPHRASE";
+const KEY_FILE: &str = "keys";
+/// Salt of the key chunks were encrypted with before the keys file
+const SALT_FILE: &str = "salt";
+
 #[derive(Debug, Deserialize)]
 struct Args {
     arg_file: Option<String>,
     arg_newname: Option<String>,
-    arg_token: Option<String>,
+    arg_token: Vec<String>,
     flag_auth: bool,
     flag_upload: bool,
     flag_download: bool,
     flag_remove: bool,
     flag_mount: bool,
+    flag_rotate_key: bool,
+    flag_change_passphrase: bool,
+    flag_retries: u32,
+    flag_jobs: usize,
+    flag_chunk_size: usize,
+    flag_cdc: bool,
+    flag_convergent: bool,
+    flag_compress: String,
 }
 
 #[cfg(feature = "persistent")]
-fn get_db() -> impl Db {
+fn get_db() -> Result<impl Db, DbError> {
     local::sqlite::Sqlite::new("db")
 }
 
 #[cfg(not(feature = "persistent"))]
-fn get_db() -> impl Db {
-    local::memory::Memory::new()
+fn get_db() -> Result<impl Db, DbError> {
+    Ok(local::memory::Memory::new())
+}
+
+fn passphrase(var: &str) -> Option<Vec<u8>> {
+    env::var_os(var).map(|p| p.as_bytes().to_vec())
+}
+
+/// Derives the key chunks were encrypted with before the keys file, from the
+/// passphrase and the salt file, if the stash has one
+fn legacy_key(passphrase: &[u8]) -> Result<Option<Key>, io::Error> {
+    let s = match std::fs::read(SALT_FILE) {
+        Ok(s) => s,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut salt = [0u8; crypto::SALT_SIZE];
+    if s.len() != salt.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a salt of {} bytes", SALT_FILE, salt.len()),
+        ));
+    }
+    salt.copy_from_slice(&s);
+    Ok(Some(Key::derive(passphrase, &salt)))
+}
+
+/// Removes the salt file once the legacy key is not to be derived anymore
+fn remove_salt() -> Result<(), io::Error> {
+    match std::fs::remove_file(SALT_FILE) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        r => r,
+    }
+}
+
+/// Unwraps the stash keys if the passphrase is given, the keys are generated
+/// on the first run and the legacy key is kept among them
+fn get_keys() -> Result<Option<KeyFile>, io::Error> {
+    let passphrase = match passphrase(PASSPHRASE_VAR) {
+        Some(p) => p,
+        None => return Ok(None),
+    };
+    let mut keys = KeyFile::open_or_create(KEY_FILE, &passphrase)?;
+    if let Some(legacy) = legacy_key(&passphrase)? {
+        if keys.add(legacy) {
+            keys.save(KEY_FILE)?;
+        }
+    }
+    Ok(Some(keys))
+}
+
+/// Wraps the stash keys with the new passphrase, chunks are left as is
+///
+/// The legacy key is derived from the old passphrase, so it's kept in the
+/// keys file and the salt file is removed.
+fn change_passphrase(old: &[u8], new: &[u8]) -> Result<(), io::Error> {
+    let mut keys = KeyFile::open(KEY_FILE, old)?;
+    if let Some(legacy) = legacy_key(old)? {
+        keys.add(legacy);
+    }
+    keys.change_passphrase(new);
+    keys.save(KEY_FILE)?;
+    remove_salt()
+}
+
+fn exit_code(e: &ServiceError) -> i32 {
+    match e {
+        ServiceError::Io(_) => 2,
+        ServiceError::Db(DbError::NotFound) => 3,
+        ServiceError::Db(_) => 4,
+        ServiceError::Provider(ProviderError::Auth) => 5,
+        ServiceError::Provider(ProviderError::QuotaExceeded) => 6,
+        ServiceError::Provider(_) => 7,
+    }
+}
+
+/// Unwraps an argument docopt leaves optional, printing usage without it
+fn required(arg: Option<String>) -> String {
+    arg.unwrap_or_else(|| {
+        eprintln!("{}", USAGE);
+        exit(1)
+    })
 }
 
 fn main() {
@@ -66,23 +207,133 @@ fn main() {
     if args.flag_auth {
         get_token::run_handler();
     }
-    let db = get_db();
-    let provider = remote::dropbox::Dropbox::new(args.arg_token.expect(USAGE));
-    if args.flag_upload {
-        service::Service { db, provider }.upload(
-            &args.arg_newname.expect(USAGE),
-            &args.arg_file.expect(USAGE),
-        );
-    } else if args.flag_download {
-        service::Service { db, provider }.download(
-            &args.arg_file.expect(USAGE),
-            &args.arg_newname.expect(USAGE),
+    if args.flag_chunk_size == 0 {
+        eprintln!("cloud-stash: chunk size must be positive");
+        exit(1);
+    }
+    if args.flag_cdc && args.flag_chunk_size < chunk::MIN_CDC_AVG {
+        eprintln!(
+            "cloud-stash: average chunk size must be at least {}",
+            chunk::MIN_CDC_AVG
         );
-    } else if args.flag_remove {
-        service::Service { db, provider }.remove(&args.arg_file.expect(USAGE));
-    } else if args.flag_mount {
-        fs::stashfs::StashFs::mount_with(db, provider, &args.arg_file.expect(USAGE));
-    } else {
+        exit(1);
+    }
+    let codec: chunk::Codec = args.flag_compress.parse().unwrap_or_else(|e| {
+        eprintln!("cloud-stash: {}", e);
+        exit(1);
+    });
+    if (args.flag_rotate_key || args.flag_change_passphrase)
+        && env::var_os(PASSPHRASE_VAR).is_none()
+    {
+        eprintln!("cloud-stash: {} must be set", PASSPHRASE_VAR);
+        exit(1);
+    }
+    if args.flag_change_passphrase {
+        let new = passphrase(NEW_PASSPHRASE_VAR).unwrap_or_else(|| {
+            eprintln!("cloud-stash: {} must be set", NEW_PASSPHRASE_VAR);
+            exit(1);
+        });
+        let old = passphrase(PASSPHRASE_VAR).unwrap_or_default();
+        if let Err(e) = change_passphrase(&old, &new) {
+            eprintln!("cloud-stash: {}", e);
+            exit(exit_code(&ServiceError::Io(e)));
+        }
+        return;
+    }
+    if args.arg_token.is_empty() {
         println!("{}", USAGE);
+        return;
+    }
+    let result = get_db().map_err(ServiceError::from).and_then(|db| {
+        let mut keys = get_keys()?;
+        if let (true, Some(keys)) = (args.flag_rotate_key, keys.as_mut()) {
+            keys.rotate();
+            keys.save(KEY_FILE)?;
+        }
+        let data_keys = keys.as_ref().map(|k| k.keys().to_vec());
+        let mode = if args.flag_convergent {
+            remote::encrypted::Mode::Convergent
+        } else {
+            remote::encrypted::Mode::Random
+        };
+        let policy = remote::retry::Policy {
+            attempts: args.flag_retries,
+            ..Default::default()
+        };
+        let jobs = max(args.flag_jobs, 1);
+        let open = |a: &String| -> Result<_, ProviderError> {
+            let p = remote::open(a.clone())?;
+            let p: Box<dyn Provider + Send> = match data_keys {
+                Some(ref keys) => {
+                    Box::new(remote::encrypted::Encrypted::new(p, keys.clone(), mode))
+                }
+                None => p,
+            };
+            let p = remote::compressed::Compressed::new(p);
+            Ok(remote::retry::Retry::new(p, policy.clone()))
+        };
+        // every account is opened once per job, so it transfers concurrently
+        let provider = remote::pool::Pool::new(
+            args.arg_token
+                .iter()
+                .map(|a| {
+                    let providers = (0..jobs).map(|_| open(a)).collect::<Result<_, _>>()?;
+                    Ok((remote::account_id(a), providers))
+                })
+                .collect::<Result<_, ProviderError>>()?,
+            jobs,
+        );
+        let mut service = service::Service {
+            db,
+            provider,
+            chunking: if args.flag_cdc {
+                chunk::Chunking::cdc(args.flag_chunk_size)
+            } else {
+                chunk::Chunking::Fixed(args.flag_chunk_size)
+            },
+            naming: data_keys.as_ref().map(|k| k[0].clone()),
+            codec,
+        };
+        if args.flag_upload {
+            service
+                .upload(&required(args.arg_newname), &required(args.arg_file))
+                .map(|u| {
+                    println!(
+                        "{} chunks uploaded, {} deduplicated",
+                        u.published, u.deduplicated
+                    )
+                })
+        } else if args.flag_download {
+            service.download(&required(args.arg_file), &required(args.arg_newname))
+        } else if args.flag_remove {
+            service.remove(&required(args.arg_file))
+        } else if args.flag_rotate_key {
+            service.reencrypt().and_then(|n| {
+                let mut keys = keys.expect("Passphrase is checked above");
+                keys.finish_rotation();
+                keys.save(KEY_FILE)?;
+                // the legacy key is not needed anymore either
+                remove_salt()?;
+                println!("{} chunks re-encrypted", n);
+                Ok(())
+            })
+        } else if args.flag_mount {
+            fs::stashfs::StashFs::mount_with(
+                service.db,
+                service.provider,
+                service.chunking,
+                service.naming,
+                service.codec,
+                &required(args.arg_file),
+            );
+            Ok(())
+        } else {
+            println!("{}", USAGE);
+            Ok(())
+        }
+    });
+    if let Err(e) = result {
+        eprintln!("cloud-stash: {}", e);
+        exit(exit_code(&e));
     }
 }
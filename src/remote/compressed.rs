@@ -0,0 +1,130 @@
+use std::io::Read;
+
+use crate::chunk::{self, Codec};
+use crate::remote::{AccountId, Provider, ProviderError};
+
+/// Compression level of zstd, the default one of the library
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compresses data of chunks with the codec where it makes them shorter, the
+/// rest are kept as is, and sets the codec of every chunk, so it is placed in
+/// the db along with the chunk
+pub fn compress(chunks: &mut [chunk::Chunk], codec: Codec) {
+    for c in chunks {
+        let compressed = match codec {
+            Codec::None => None,
+            Codec::Zstd => zstd::encode_all(&c.chunk[..], ZSTD_LEVEL).ok(),
+        };
+        match compressed {
+            Some(data) if data.len() < c.chunk.len() => {
+                c.chunk = data;
+                c.codec = codec;
+            }
+            _ => c.codec = Codec::None,
+        }
+    }
+}
+
+/// Decompresses received chunks with the codec recorded for them, published
+/// chunks are passed as they are
+///
+/// Chunks are published without any header, so chunks of `Codec::None` are
+/// the same objects as those published before compression was supported.
+pub struct Compressed<P: Provider> {
+    inner: P,
+}
+
+impl<P: Provider> Compressed<P> {
+    pub fn new(inner: P) -> Compressed<P> {
+        Compressed { inner }
+    }
+
+    /// Decompresses at most one byte past the chunk length, so the length
+    /// check catches a malformed chunk without inflating it completely
+    fn decompress(
+        &self,
+        c: &chunk::Stored,
+        data: chunk::Data,
+    ) -> Result<chunk::Data, ProviderError> {
+        match c.codec {
+            Codec::None => Ok(data),
+            Codec::Zstd => {
+                let mut r = Vec::with_capacity(c.len);
+                zstd::Decoder::new(&data[..])
+                    .and_then(|d| d.take(c.len as u64 + 1).read_to_end(&mut r))
+                    .map_err(|_| ProviderError::Corrupted(c.hash.clone()))?;
+                Ok(r)
+            }
+        }
+    }
+}
+
+impl<P: Provider> Provider for Compressed<P> {
+    fn publish(&mut self, s: &chunk::Chunk) -> Result<AccountId, ProviderError> {
+        self.inner.publish(s)
+    }
+
+    fn publish_batch(&mut self, cs: &[chunk::Chunk]) -> Result<Vec<AccountId>, ProviderError> {
+        self.inner.publish_batch(cs)
+    }
+
+    fn receive(&mut self, c: &chunk::Stored) -> Result<chunk::Data, ProviderError> {
+        let data = self.inner.receive(c)?;
+        self.decompress(c, data)
+    }
+
+    fn receive_batch(&mut self, cs: &[chunk::Stored]) -> Result<Vec<chunk::Data>, ProviderError> {
+        self.inner
+            .receive_batch(cs)?
+            .into_iter()
+            .zip(cs)
+            .map(|(data, c)| self.decompress(c, data))
+            .collect()
+    }
+
+    fn delete(&mut self, cs: &[chunk::Stored]) -> Result<(), ProviderError> {
+        self.inner.delete(cs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::{compress, Compressed};
+    use crate::chunk::{self, Codec};
+    use crate::remote::directory::Directory;
+    use crate::remote::Provider;
+    use crate::testing::{chunk_of, random_blob, tmpdir};
+
+    fn publish_receive(codec: Codec, block: Vec<u8>) -> usize {
+        let root = tmpdir();
+        let mut p = Compressed::new(Directory::new(&root).unwrap());
+        let mut chunks = vec![chunk_of(&block)];
+        compress(&mut chunks, codec);
+        let c = &chunks[0];
+        let stored = chunk::Stored {
+            hash: c.hash.clone(),
+            name: c.name.clone(),
+            account: p.publish(c).unwrap(),
+            len: block.len(),
+            codec: c.codec,
+        };
+        let published = fs::read(root.join(c.name.to_string())).unwrap();
+        if c.codec == Codec::None {
+            assert_eq!(published, block);
+        }
+        assert_eq!(p.receive(&stored).unwrap(), block);
+        assert_eq!(p.receive_batch(&[stored]).unwrap(), vec![block]);
+        fs::remove_dir_all(&root).unwrap();
+        published.len()
+    }
+
+    #[test]
+    fn compress_when_shorter() {
+        let text = b"text compresses well ".repeat(100);
+        assert!(publish_receive(Codec::Zstd, text.clone()) < text.len() / 4);
+        assert_eq!(publish_receive(Codec::None, text.clone()), text.len());
+        assert_eq!(publish_receive(Codec::Zstd, random_blob(2048)), 2048);
+    }
+}
@@ -0,0 +1,76 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::*;
+
+use crate::chunk;
+use crate::crypto::Hash;
+use crate::remote::{deleted, AccountId, Provider, ProviderError};
+
+/// Stores chunks as files named by their remote names under a local directory
+#[derive(Debug)]
+pub struct Directory {
+    root: PathBuf,
+}
+
+impl Directory {
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<Directory, ProviderError> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root)?;
+        Ok(Directory { root })
+    }
+
+    fn path(&self, h: &Hash) -> PathBuf {
+        self.root.join(h.to_string())
+    }
+}
+
+impl Provider for Directory {
+    fn publish(&mut self, s: &chunk::Chunk) -> Result<AccountId, ProviderError> {
+        let path = self.path(&s.name);
+        trace!("publish {:?}", path);
+        fs::write(&path, &s.chunk[..])?;
+        Ok(0)
+    }
+
+    fn receive(&mut self, c: &chunk::Stored) -> Result<chunk::Data, ProviderError> {
+        let path = self.path(&c.name);
+        trace!("receive {:?}", path);
+        Ok(fs::read(&path)?)
+    }
+
+    fn delete(&mut self, cs: &[chunk::Stored]) -> Result<(), ProviderError> {
+        for path in cs.iter().map(|c| self.path(&c.name)) {
+            trace!("delete {:?}", path);
+            deleted(fs::remove_file(&path).map_err(ProviderError::from))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::Directory;
+    use crate::chunk;
+    use crate::remote::{Provider, ProviderError};
+    use crate::testing::{publish_receive, tmpdir};
+
+    #[test]
+    fn publish_receive_delete() {
+        let root = tmpdir();
+        let mut dir = Directory::new(&root).unwrap();
+        let stored = publish_receive(&mut dir, &[7u8; chunk::CHUNK_SIZE]);
+        assert!(root.join(stored.hash.to_string()).is_file());
+
+        dir.delete(&[stored.clone()]).unwrap();
+        assert!(!root.join(stored.hash.to_string()).exists());
+        dir.delete(&[stored.clone()]).unwrap();
+        match dir.receive(&stored) {
+            Err(ProviderError::NotFound) => {}
+            r => panic!("Unexpected {:?}", r.map(|_| ())),
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
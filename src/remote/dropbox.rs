@@ -1,25 +1,87 @@
+use std::io::Read;
+use std::thread;
+use std::time::Duration;
+
 use log::*;
 use reqwest;
 use reqwest::header::{HeaderName, CONNECTION, CONTENT_TYPE};
-use serde_json::*;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
 
 use crate::chunk;
 use crate::crypto::Hash;
-use crate::remote::Provider;
+use crate::remote::{check, AccountId, Provider, ProviderError};
+
+const API: &str = "https://api.dropboxapi.com/2";
+const CONTENT: &str = "https://content.dropboxapi.com/2";
 
 #[derive(Debug)]
 pub struct Dropbox {
     token: String,
+    client: reqwest::Client,
 }
 
 impl<'a> Dropbox {
     pub fn new(s: String) -> Dropbox {
-        Dropbox { token: s }
+        Dropbox {
+            token: s,
+            client: reqwest::Client::new(),
+        }
     }
 
     pub fn token(&'a self) -> &'a str {
         &self.token
     }
+
+    /// Calls an RPC endpoint taking and returning JSON
+    fn rpc(&self, endpoint: &str, arg: &Value) -> Result<Value, ProviderError> {
+        let mut res = self
+            .client
+            .post(&format!("{}/{}", API, endpoint))
+            .bearer_auth(self.token())
+            .json(arg)
+            .send()?;
+        debug!("{:?}", res);
+        if let Some(summary) = conflict(&mut res) {
+            return Err(error(&summary));
+        }
+        Ok(check(res)?.json()?)
+    }
+
+    /// Calls a content endpoint with the argument passed in the header,
+    /// 409 responses are left to the caller
+    fn content(
+        &self,
+        endpoint: &str,
+        arg: &Value,
+        body: Vec<u8>,
+    ) -> Result<reqwest::Response, ProviderError> {
+        let res = self
+            .client
+            .post(&format!("{}/{}", CONTENT, endpoint))
+            .bearer_auth(self.token())
+            .header(HeaderName::from_static(DROPBOX_HDR), arg.to_string())
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .body(body)
+            .send()?;
+        debug!("{:?}", res);
+        Ok(res)
+    }
+
+    /// Waits until the batch job launched by `endpoint` is finished
+    fn wait(&self, endpoint: &str, mut status: Value) -> Result<Value, ProviderError> {
+        if let Some(id) = status["async_job_id"].as_str().map(str::to_owned) {
+            let job = json!({ "async_job_id": id });
+            loop {
+                thread::sleep(POLL_INTERVAL);
+                status = self.rpc(&format!("{}/check", endpoint), &job)?;
+                if status[".tag"] != "in_progress" {
+                    break;
+                }
+            }
+        }
+        Ok(status)
+    }
 }
 
 #[derive(Clone)]
@@ -27,63 +89,228 @@ struct DropboxApiArg {
     val: Value,
 }
 
-const DROPBOX_HDR: &str = "Dropbox-API-Arg";
+// `HeaderName::from_static` accepts lowercase names only
+const DROPBOX_HDR: &str = "dropbox-api-arg";
+/// Maximum number of entries in a single `delete_batch` or `finish_batch`
+const BATCH: usize = 1000;
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Dropbox reports endpoint specific errors with 409 status and a summary
+/// like `path/not_found/..` in the body
+fn conflict(res: &mut reqwest::Response) -> Option<String> {
+    if res.status() != StatusCode::CONFLICT {
+        return None;
+    }
+    let body: Option<Value> = res.json().ok();
+    Some(
+        body.and_then(|b| b["error_summary"].as_str().map(str::to_owned))
+            .unwrap_or_default(),
+    )
+}
+
+fn error(summary: &str) -> ProviderError {
+    warn!("Dropbox error: {}", summary);
+    if summary.contains("not_found") {
+        ProviderError::NotFound
+    } else if summary.contains("insufficient_space") {
+        ProviderError::QuotaExceeded
+    } else if summary.contains("too_many_write_operations") {
+        ProviderError::RateLimited(None)
+    } else {
+        ProviderError::Network(summary.to_owned())
+    }
+}
+
+/// Joins nested `.tag` fields of a union value, like in `error_summary`
+fn summary(v: &Value) -> String {
+    let mut tags = Vec::new();
+    let mut v = v;
+    while let Some(tag) = v[".tag"].as_str() {
+        tags.push(tag);
+        v = &v[tag];
+    }
+    tags.join("/")
+}
+
+/// Matches entries of a finished batch job with the chunks it was launched
+/// for, failures with summary containing `expected` are not reported
+fn failures<'a, I>(
+    hashes: I,
+    status: &Value,
+    expected: &str,
+) -> Result<Vec<(Hash, ProviderError)>, ProviderError>
+where
+    I: ExactSizeIterator<Item = &'a Hash>,
+{
+    if status[".tag"] != "complete" {
+        return Err(error(&summary(status)));
+    }
+    let entries = status["entries"]
+        .as_array()
+        .filter(|es| es.len() == hashes.len())
+        .ok_or_else(|| ProviderError::Network("malformed batch result".to_owned()))?;
+    Ok(hashes
+        .zip(entries)
+        .filter(|(_, e)| e[".tag"] != "success")
+        .map(|(h, e)| (h, summary(&e["failure"])))
+        .filter(|(_, s)| !s.contains(expected))
+        .map(|(h, s)| (h.clone(), error(&s)))
+        .collect())
+}
+
+fn partial(failed: Vec<(Hash, ProviderError)>) -> Result<(), ProviderError> {
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(ProviderError::Partial(failed))
+    }
+}
 
 impl Provider for Dropbox {
-    fn publish(&mut self, s: &chunk::Chunk) {
-        let client = reqwest::Client::new();
-        let res = client
-            .post("https://content.dropboxapi.com/2/files/upload")
-            .bearer_auth(self.token().to_owned())
-            .header(
-                HeaderName::from_static(DROPBOX_HDR),
-                json!({"path": format!("/{}", &s.hash),
-                            "mode": "add",
-                            "autorename": false})
-                .to_string(),
-            )
-            .header(CONTENT_TYPE, "application/octet-stream")
-            .body(s.chunk.to_vec())
-            .send()
-            .unwrap();
-        debug!("{:?}", res);
+    fn publish(&mut self, s: &chunk::Chunk) -> Result<AccountId, ProviderError> {
+        let mut res = self.content(
+            "files/upload",
+            &json!({"path": format!("/{}", &s.name),
+                    "mode": "add",
+                    "autorename": false}),
+            s.chunk.to_vec(),
+        )?;
+        match conflict(&mut res) {
+            // chunks are content addressed, so the existing file is the same
+            Some(ref summary) if summary.starts_with("path/conflict") => Ok(0),
+            Some(summary) => Err(error(&summary)),
+            None => check(res).map(|_| 0),
+        }
+    }
+
+    /// Uploads every chunk into its own closed session and commits them all
+    /// with a single `finish_batch` call
+    fn publish_batch(&mut self, cs: &[chunk::Chunk]) -> Result<Vec<AccountId>, ProviderError> {
+        let mut failed = Vec::new();
+        for batch in cs.chunks(BATCH) {
+            let mut entries = Vec::with_capacity(batch.len());
+            for c in batch {
+                let mut res = self.content(
+                    "files/upload_session/start",
+                    &json!({ "close": true }),
+                    c.chunk.to_vec(),
+                )?;
+                if let Some(summary) = conflict(&mut res) {
+                    return Err(error(&summary));
+                }
+                let session: Value = check(res)?.json()?;
+                entries.push(json!({
+                    "cursor": {"session_id": session["session_id"], "offset": c.chunk.len()},
+                    "commit": {"path": format!("/{}", &c.name),
+                               "mode": "add",
+                               "autorename": false},
+                }));
+            }
+            let status = self.rpc(
+                "files/upload_session/finish_batch",
+                &json!({ "entries": entries }),
+            )?;
+            let status = self.wait("files/upload_session/finish_batch", status)?;
+            // chunks are content addressed, so the existing file is the same
+            failed.extend(failures(
+                batch.iter().map(|c| &c.hash),
+                &status,
+                "path/conflict",
+            )?);
+        }
+        partial(failed).map(|_| vec![0; cs.len()])
     }
 
-    fn receive(&mut self, h: &Hash) -> chunk::Data {
-        let client = reqwest::Client::new();
-        let mut res = client
-            .post("https://content.dropboxapi.com/2/files/download")
-            .bearer_auth(self.token().to_owned())
+    fn receive(&mut self, c: &chunk::Stored) -> Result<chunk::Data, ProviderError> {
+        let mut res = self
+            .client
+            .post(&format!("{}/files/download", CONTENT))
+            .bearer_auth(self.token())
             .header(
                 HeaderName::from_static(DROPBOX_HDR),
-                json!({ "path": format!("/{}", &h) }).to_string(),
+                json!({ "path": format!("/{}", &c.name) }).to_string(),
             )
             .header(CONNECTION, "close")
-            .send()
-            .unwrap();
-        debug!("{:?}", res);
-        let mut buf = Vec::new();
-        assert_eq!(res.copy_to(&mut buf).unwrap(), chunk::CHUNK_SIZE as u64);
-        let mut r = [0u8; chunk::CHUNK_SIZE];
-        r.copy_from_slice(&buf[..chunk::CHUNK_SIZE]);
-        r
-    }
-
-    fn delete(&mut self, hs: &[Hash]) {
-        let client = reqwest::Client::new();
-        let res = client
-            .post("https://content.dropboxapi.com/2/files/download")
-            .bearer_auth(self.token().to_owned())
-            .header(
-                HeaderName::from_static(DROPBOX_HDR),
-                json!({
-                    "entries": serde_json::Value::Array(
-                        hs.iter()
-                          .map(|h| json!({"path": format!("/{}", &h)})).collect())})
-                .to_string(),
-            )
-            .send()
-            .unwrap();
+            .send()?;
         debug!("{:?}", res);
+        if let Some(summary) = conflict(&mut res) {
+            return Err(error(&summary));
+        }
+        let mut r = Vec::new();
+        check(res)?.read_to_end(&mut r)?;
+        Ok(r)
+    }
+
+    fn delete(&mut self, cs: &[chunk::Stored]) -> Result<(), ProviderError> {
+        let mut failed = Vec::new();
+        for batch in cs.chunks(BATCH) {
+            let entries: Vec<_> = batch
+                .iter()
+                .map(|c| json!({ "path": format!("/{}", &c.name) }))
+                .collect();
+            let status = self.rpc("files/delete_batch", &json!({ "entries": entries }))?;
+            let status = self.wait("files/delete_batch", status)?;
+            // chunks which are already gone are deleted as well
+            failed.extend(failures(
+                batch.iter().map(|c| &c.hash),
+                &status,
+                "not_found",
+            )?);
+        }
+        partial(failed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::{error, failures};
+    use crate::crypto;
+    use crate::remote::ProviderError;
+
+    #[test]
+    fn error_summary() {
+        match error("path/not_found/..") {
+            ProviderError::NotFound => {}
+            e => panic!("Unexpected {:?}", e),
+        }
+        match error("path/insufficient_space/...") {
+            ProviderError::QuotaExceeded => {}
+            e => panic!("Unexpected {:?}", e),
+        }
+        match error("path/too_many_write_operations/") {
+            ProviderError::RateLimited(None) => {}
+            e => panic!("Unexpected {:?}", e),
+        }
+    }
+
+    #[test]
+    fn batch_failures() {
+        let hs: Vec<_> = (0..4u8).map(|i| crypto::hash(&[i])).collect();
+        let status = json!({
+            ".tag": "complete",
+            "entries": [
+                {".tag": "success", "metadata": {}},
+                {".tag": "failure", "failure": {".tag": "path_lookup",
+                                                "path_lookup": {".tag": "not_found"}}},
+                {".tag": "failure", "failure": {".tag": "too_many_write_operations"}},
+                {".tag": "success", "metadata": {}},
+            ]
+        });
+        match failures(hs.iter(), &status, "not_found")
+            .unwrap()
+            .as_slice()
+        {
+            [(h, ProviderError::RateLimited(None))] => assert_eq!(*h, hs[2]),
+            r => panic!("Unexpected {:?}", r),
+        }
+        let failed = json!({".tag": "failed", "failed": {".tag": "too_many_write_operations"}});
+        match failures(hs.iter(), &failed, "not_found") {
+            Err(ProviderError::RateLimited(None)) => {}
+            r => panic!("Unexpected {:?}", r),
+        }
+        let empty = json!({".tag": "complete", "entries": []});
+        assert!(failures(hs.iter(), &empty, "not_found").is_err());
     }
 }
@@ -0,0 +1,181 @@
+use crate::chunk;
+use crate::crypto::Key;
+use crate::remote::{AccountId, Provider, ProviderError};
+
+/// Way chunks are encrypted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Stash key with a random nonce, equal chunks give different ciphertexts
+    Random,
+    /// Key derived from the chunk hash and the stash key, equal chunks give
+    /// equal ciphertexts, so they are still deduplicated by name on the
+    /// remote side while staying unreadable without the stash key
+    Convergent,
+}
+
+/// Encrypts chunks before they are published by the wrapped provider and
+/// authenticates and decrypts received ones
+///
+/// Chunks are encrypted with the first key in the given mode, received ones
+/// are decrypted with any of the keys in either mode, so chunks of previous
+/// keys stay readable during rotation and the mode can differ between runs.
+pub struct Encrypted<P: Provider> {
+    inner: P,
+    keys: Vec<Key>,
+    mode: Mode,
+}
+
+impl<P: Provider> Encrypted<P> {
+    pub fn new(inner: P, keys: Vec<Key>, mode: Mode) -> Encrypted<P> {
+        assert!(!keys.is_empty(), "Encrypted requires at least one key");
+        Encrypted { inner, keys, mode }
+    }
+
+    fn seal(&self, c: &chunk::Chunk) -> chunk::Chunk {
+        chunk::Chunk {
+            hash: c.hash.clone(),
+            name: c.name.clone(),
+            chunk: match self.mode {
+                Mode::Random => self.keys[0].seal(&c.chunk),
+                Mode::Convergent => self.keys[0].seal_convergent(&c.hash, &c.chunk),
+            },
+            idx: c.idx,
+            codec: c.codec,
+        }
+    }
+
+    fn open(&self, c: &chunk::Stored, sealed: &[u8]) -> Result<chunk::Data, ProviderError> {
+        let convergent = |key: &Key| key.chunk_key(&c.hash).open(sealed);
+        self.keys
+            .iter()
+            .filter_map(|key| match self.mode {
+                Mode::Random => key.open(sealed).or_else(|| convergent(key)),
+                Mode::Convergent => convergent(key).or_else(|| key.open(sealed)),
+            })
+            .next()
+            .ok_or_else(|| ProviderError::Corrupted(c.hash.clone()))
+    }
+}
+
+impl<P: Provider> Provider for Encrypted<P> {
+    fn publish(&mut self, s: &chunk::Chunk) -> Result<AccountId, ProviderError> {
+        let sealed = self.seal(s);
+        self.inner.publish(&sealed)
+    }
+
+    fn publish_batch(&mut self, cs: &[chunk::Chunk]) -> Result<Vec<AccountId>, ProviderError> {
+        let sealed: Vec<_> = cs.iter().map(|c| self.seal(c)).collect();
+        self.inner.publish_batch(&sealed)
+    }
+
+    fn receive(&mut self, c: &chunk::Stored) -> Result<chunk::Data, ProviderError> {
+        let sealed = self.inner.receive(c)?;
+        self.open(c, &sealed)
+    }
+
+    fn receive_batch(&mut self, cs: &[chunk::Stored]) -> Result<Vec<chunk::Data>, ProviderError> {
+        self.inner
+            .receive_batch(cs)?
+            .iter()
+            .zip(cs)
+            .map(|(sealed, c)| self.open(c, sealed))
+            .collect()
+    }
+
+    fn delete(&mut self, cs: &[chunk::Stored]) -> Result<(), ProviderError> {
+        self.inner.delete(cs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::{Encrypted, Mode};
+    use crate::chunk;
+    use crate::crypto::{Key, SALT_SIZE};
+    use crate::remote::directory::Directory;
+    use crate::remote::{Provider, ProviderError};
+    use crate::testing::{chunk_of, publish_receive, tmpdir};
+
+    fn encrypt_published(mode: Mode) {
+        let root = tmpdir();
+        let key = Key::derive(b"passphrase", &[0; SALT_SIZE]);
+        let mut p = Encrypted::new(Directory::new(&root).unwrap(), vec![key], mode);
+        let block = vec![7u8; chunk::CHUNK_SIZE];
+        let stored = publish_receive(&mut p, &block);
+        let raw = fs::read(root.join(stored.hash.to_string())).unwrap();
+        assert!(raw.windows(16).all(|w| w != &block[..16]));
+        assert_eq!(p.receive_batch(&[stored.clone()]).unwrap(), vec![block]);
+
+        let key = Key::derive(b"another", &[0; SALT_SIZE]);
+        let mut other = Encrypted::new(Directory::new(&root).unwrap(), vec![key.clone()], mode);
+        match other.receive(&stored) {
+            Err(ProviderError::Corrupted(ref h)) if *h == stored.hash => {}
+            r => panic!("Unexpected {:?}", r.map(|_| ())),
+        }
+        let mut rotated = Encrypted::new(
+            Directory::new(&root).unwrap(),
+            vec![key, p.keys[0].clone()],
+            mode,
+        );
+        assert_eq!(
+            rotated.receive(&stored).unwrap(),
+            vec![7u8; chunk::CHUNK_SIZE]
+        );
+
+        p.delete(&[stored]).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn encrypt_random() {
+        encrypt_published(Mode::Random);
+    }
+
+    #[test]
+    fn encrypt_convergent() {
+        encrypt_published(Mode::Convergent);
+    }
+
+    #[test]
+    fn open_either_mode() {
+        let root = tmpdir();
+        let key = Key::derive(b"passphrase", &[0; SALT_SIZE]);
+        let encrypted =
+            |mode| Encrypted::new(Directory::new(&root).unwrap(), vec![key.clone()], mode);
+        let stored: Vec<_> = [(1u8, Mode::Random), (2u8, Mode::Convergent)]
+            .iter()
+            .map(|&(i, mode)| {
+                let c = chunk_of(&[i; chunk::CHUNK_SIZE]);
+                chunk::Stored {
+                    hash: c.hash.clone(),
+                    name: c.name.clone(),
+                    account: encrypted(mode).publish(&c).unwrap(),
+                    len: c.chunk.len(),
+                    codec: chunk::Codec::None,
+                }
+            })
+            .collect();
+        for mode in &[Mode::Random, Mode::Convergent] {
+            let received = encrypted(*mode).receive_batch(&stored).unwrap();
+            assert_eq!(received[0], vec![1u8; chunk::CHUNK_SIZE]);
+            assert_eq!(received[1], vec![2u8; chunk::CHUNK_SIZE]);
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn converge_equal_chunks() {
+        let root = tmpdir();
+        let key = Key::derive(b"passphrase", &[0; SALT_SIZE]);
+        let mut p = Encrypted::new(Directory::new(&root).unwrap(), vec![key], Mode::Convergent);
+        let c = chunk_of(&[7u8; chunk::CHUNK_SIZE]);
+        let path = root.join(c.hash.to_string());
+        p.publish(&c).unwrap();
+        let first = fs::read(&path).unwrap();
+        p.publish(&c).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), first);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::io::Read;
+
+use log::*;
+use reqwest;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+use crate::chunk;
+use crate::crypto::Hash;
+use crate::remote::{self, deleted, AccountId, Provider, ProviderError};
+
+const API: &str = "https://www.googleapis.com";
+const BOUNDARY: &str = "cloud-stash-chunk-boundary";
+
+/// Client of Google Drive v3 API
+///
+/// Chunks are kept in the hidden application data folder. Drive addresses
+/// files by ids rather than names, so ids of the chunks are looked up by
+/// name once and cached. Drive allows several files of the same name, so
+/// a chunk is published only if no file has its name yet.
+#[derive(Debug)]
+pub struct GDrive {
+    token: String,
+    api: String,
+    client: reqwest::Client,
+    ids: HashMap<Hash, String>,
+}
+
+/// Builds `multipart/related` upload body with chunk metadata and content
+fn multipart(h: &Hash, data: &[u8]) -> Vec<u8> {
+    let metadata = json!({"name": h.to_string(), "parents": ["appDataFolder"]});
+    let mut body = format!(
+        concat!(
+            "--{b}\r\n",
+            "Content-Type: application/json; charset=UTF-8\r\n\r\n",
+            "{m}\r\n",
+            "--{b}\r\n",
+            "Content-Type: application/octet-stream\r\n\r\n"
+        ),
+        b = BOUNDARY,
+        m = metadata
+    )
+    .into_bytes();
+    body.extend_from_slice(data);
+    body.extend_from_slice(format!("\r\n--{}--", BOUNDARY).as_bytes());
+    body
+}
+
+/// Drive answers 403 both to denied requests and to exhausted quota, the
+/// reason is only given in the error body
+fn forbidden(body: &Value) -> ProviderError {
+    match body["error"]["errors"][0]["reason"].as_str() {
+        Some("storageQuotaExceeded") => ProviderError::QuotaExceeded,
+        _ => ProviderError::Auth,
+    }
+}
+
+fn check(mut res: reqwest::Response) -> Result<reqwest::Response, ProviderError> {
+    if res.status() != StatusCode::FORBIDDEN {
+        return remote::check(res);
+    }
+    Err(forbidden(&res.json().unwrap_or(Value::Null)))
+}
+
+impl GDrive {
+    pub fn new(token: String) -> GDrive {
+        GDrive::with_api(token, API.to_owned())
+    }
+
+    pub fn with_api(token: String, api: String) -> GDrive {
+        GDrive {
+            token,
+            api,
+            client: reqwest::Client::new(),
+            ids: HashMap::new(),
+        }
+    }
+
+    fn files(&self) -> String {
+        format!("{}/drive/v3/files", self.api)
+    }
+
+    /// Ids of every file named by the hash
+    fn find(&self, h: &Hash) -> Result<Vec<String>, ProviderError> {
+        let res = self
+            .client
+            .get(&self.files())
+            .bearer_auth(&self.token)
+            .query(&[
+                ("spaces", "appDataFolder"),
+                ("q", &format!("name = '{}' and trashed = false", h)),
+                ("fields", "files(id)"),
+            ])
+            .send()?;
+        debug!("{:?}", res);
+        let list: Value = check(res)?.json()?;
+        Ok(list["files"]
+            .as_array()
+            .map(|files| {
+                files
+                    .iter()
+                    .filter_map(|f| f["id"].as_str().map(str::to_owned))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn id(&mut self, h: &Hash) -> Result<String, ProviderError> {
+        if let Some(id) = self.ids.get(h) {
+            return Ok(id.clone());
+        }
+        let id = self
+            .find(h)?
+            .into_iter()
+            .next()
+            .ok_or(ProviderError::NotFound)?;
+        self.ids.insert(h.clone(), id.clone());
+        Ok(id)
+    }
+}
+
+impl Provider for GDrive {
+    fn publish(&mut self, s: &chunk::Chunk) -> Result<AccountId, ProviderError> {
+        // chunks are content addressed, so the existing file is the same
+        match self.id(&s.name) {
+            Ok(_) => return Ok(0),
+            Err(ProviderError::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+        let res = self
+            .client
+            .post(&format!("{}/upload/drive/v3/files", self.api))
+            .bearer_auth(&self.token)
+            .query(&[("uploadType", "multipart"), ("fields", "id")])
+            .header(
+                CONTENT_TYPE,
+                format!("multipart/related; boundary={}", BOUNDARY),
+            )
+            .body(multipart(&s.name, &s.chunk))
+            .send()?;
+        debug!("{:?}", res);
+        let file: Value = check(res)?.json()?;
+        if let Some(id) = file["id"].as_str() {
+            self.ids.insert(s.name.clone(), id.to_owned());
+        }
+        Ok(0)
+    }
+
+    fn receive(&mut self, c: &chunk::Stored) -> Result<chunk::Data, ProviderError> {
+        let id = self.id(&c.name)?;
+        let res = self
+            .client
+            .get(&format!("{}/{}", self.files(), id))
+            .bearer_auth(&self.token)
+            .query(&[("alt", "media")])
+            .send()?;
+        debug!("{:?}", res);
+        let mut r = Vec::new();
+        check(res)?.read_to_end(&mut r)?;
+        Ok(r)
+    }
+
+    /// Every file of the chunk name is deleted, including duplicates left
+    /// by earlier versions
+    fn delete(&mut self, cs: &[chunk::Stored]) -> Result<(), ProviderError> {
+        for c in cs {
+            for id in self.find(&c.name)? {
+                let res = self
+                    .client
+                    .delete(&format!("{}/{}", self.files(), id))
+                    .bearer_auth(&self.token)
+                    .send()?;
+                debug!("{:?}", res);
+                deleted(check(res))?;
+            }
+            self.ids.remove(&c.name);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{forbidden, multipart, GDrive};
+    use crate::chunk;
+    use crate::crypto;
+    use crate::remote::stub::Stub;
+    use crate::remote::{Provider, ProviderError};
+    use crate::testing::{chunk_of, publish_receive};
+    use serde_json::json;
+
+    #[test]
+    fn publish_receive_delete() {
+        let stub = Stub::default();
+        let api = stub.serve();
+        let block = [6u8; chunk::CHUNK_SIZE];
+        let stored = publish_receive(
+            &mut GDrive::with_api("token".to_owned(), api.clone()),
+            &block,
+        );
+        // a new client finds the file instead of uploading a duplicate
+        let mut drive = GDrive::with_api("token".to_owned(), api);
+        drive.publish(&chunk_of(&block)).unwrap();
+        assert_eq!(stub.objects.lock().unwrap().len(), 1);
+
+        // a duplicate uploaded by an earlier version
+        let duplicate = "/drive/v3/files/duplicate".to_owned();
+        stub.objects
+            .lock()
+            .unwrap()
+            .insert(duplicate.clone(), block.to_vec());
+        let name = stored.name.to_string();
+        stub.names.lock().unwrap().insert(duplicate, name);
+        drive.delete(&[stored.clone()]).unwrap();
+        assert!(stub.objects.lock().unwrap().is_empty());
+        drive.delete(&[stored.clone()]).unwrap();
+        match drive.receive(&stored) {
+            Err(ProviderError::NotFound) => {}
+            r => panic!("Unexpected {:?}", r.map(|_| ())),
+        }
+        let requests = stub.requests.lock().unwrap();
+        assert_eq!(requests.iter().filter(|r| r.method == "POST").count(), 1);
+        assert!(requests
+            .iter()
+            .all(|r| r.headers["authorization"] == "Bearer token"));
+    }
+
+    #[test]
+    fn multipart_body() {
+        let h = crypto::hash(b"chunk");
+        let body = String::from_utf8(multipart(&h, b"chunk")).unwrap();
+        let parts: Vec<_> = body.split("--cloud-stash-chunk-boundary").collect();
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts[0], "");
+        assert!(parts[1].contains(&format!("\"name\":\"{}\"", h)));
+        assert!(parts[1].contains("\"parents\":[\"appDataFolder\"]"));
+        assert!(parts[2].ends_with("\r\n\r\nchunk\r\n"));
+        assert_eq!(parts[3], "--");
+    }
+
+    #[test]
+    fn forbidden_reason() {
+        let quota = json!({"error": {"errors": [{"reason": "storageQuotaExceeded"}]}});
+        let denied = json!({"error": {"errors": [{"reason": "insufficientPermissions"}]}});
+        match forbidden(&quota) {
+            ProviderError::QuotaExceeded => {}
+            e => panic!("Unexpected {:?}", e),
+        }
+        for body in &[denied, json!(null)] {
+            match forbidden(body) {
+                ProviderError::Auth => {}
+                e => panic!("Unexpected {:?}", e),
+            }
+        }
+    }
+}
@@ -1,10 +1,219 @@
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use reqwest::header::RETRY_AFTER;
+use reqwest::StatusCode;
+
 use crate::chunk;
-use crate::crypto::Hash;
+use crate::crypto::{self, Hash};
 
+pub mod compressed;
+pub mod directory;
 pub mod dropbox;
+pub mod encrypted;
+pub mod gdrive;
+pub mod onedrive;
+pub mod pool;
+pub mod retry;
+pub mod s3;
+#[cfg(feature = "sftp")]
+pub mod sftp;
+#[cfg(test)]
+mod stub;
+pub mod webdav;
+
+/// Identity of the account a chunk is published on, derived from the account
+/// string by `account_id`, so it doesn't depend on the order of accounts
+///
+/// Chunks published before accounts were identified have account 0 and live
+/// on the first given account.
+pub type AccountId = u32;
+
+#[derive(Debug)]
+pub enum ProviderError {
+    /// Remote host is unreachable or the transfer is broken
+    Network(String),
+    /// Credentials are rejected
+    Auth,
+    /// No space left on the account
+    QuotaExceeded,
+    /// Requested chunk is absent
+    NotFound,
+    /// Too many requests, optionally with a delay before the next attempt
+    RateLimited(Option<Duration>),
+    /// Operation failed for some of the chunks only
+    Partial(Vec<(Hash, ProviderError)>),
+    /// Received chunk failed authentication
+    Corrupted(Hash),
+    /// Received chunk doesn't match its hash
+    Mismatch(Hash),
+    /// Chunk lives on an account which is not given
+    UnknownAccount(AccountId),
+    /// Account string or the environment it's opened with is malformed
+    Invalid(String),
+}
+
+impl fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProviderError::Network(e) => write!(f, "network failure: {}", e),
+            ProviderError::Auth => write!(f, "authorization failed"),
+            ProviderError::QuotaExceeded => write!(f, "storage quota exceeded"),
+            ProviderError::NotFound => write!(f, "chunk not found"),
+            ProviderError::RateLimited(None) => write!(f, "too many requests"),
+            ProviderError::RateLimited(Some(d)) => {
+                write!(f, "too many requests, retry after {}s", d.as_secs())
+            }
+            ProviderError::Partial(es) => {
+                write!(f, "{} chunks failed", es.len())?;
+                es.iter()
+                    .try_for_each(|(h, e)| write!(f, "\n  {}: {}", h, e))
+            }
+            ProviderError::Corrupted(h) => {
+                write!(f, "chunk {} is corrupted or encrypted with another key", h)
+            }
+            ProviderError::Mismatch(h) => {
+                write!(f, "received chunk {} doesn't match its hash", h)
+            }
+            ProviderError::UnknownAccount(a) => {
+                write!(f, "chunk lives on account {:08x} which is not given", a)
+            }
+            ProviderError::Invalid(e) => write!(f, "invalid account: {}", e),
+        }
+    }
+}
+
+impl From<reqwest::Error> for ProviderError {
+    fn from(e: reqwest::Error) -> Self {
+        ProviderError::Network(e.to_string())
+    }
+}
+
+impl From<io::Error> for ProviderError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => ProviderError::NotFound,
+            io::ErrorKind::PermissionDenied => ProviderError::Auth,
+            _ if e.raw_os_error() == Some(libc::ENOSPC) => ProviderError::QuotaExceeded,
+            _ => ProviderError::Network(e.to_string()),
+        }
+    }
+}
+
+/// Maps unsuccessful HTTP response to the corresponding error
+pub fn check(res: reqwest::Response) -> Result<reqwest::Response, ProviderError> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+    Err(match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => ProviderError::Auth,
+        StatusCode::NOT_FOUND | StatusCode::GONE => ProviderError::NotFound,
+        StatusCode::INSUFFICIENT_STORAGE => ProviderError::QuotaExceeded,
+        StatusCode::TOO_MANY_REQUESTS => ProviderError::RateLimited(
+            res.headers()
+                .get(RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok())
+                .map(Duration::from_secs),
+        ),
+        _ => ProviderError::Network(format!("{} responded with {}", res.url(), status)),
+    })
+}
+
+/// Treats a chunk which is already gone as deleted, so a batch interrupted
+/// halfway can be deleted again
+pub fn deleted<T>(r: Result<T, ProviderError>) -> Result<(), ProviderError> {
+    match r {
+        Ok(_) | Err(ProviderError::NotFound) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
 
 pub trait Provider {
-    fn publish(&mut self, s: &chunk::Chunk);
-    fn receive(&mut self, h: &Hash) -> chunk::Data;
-    fn delete(&mut self, hs: &[Hash]);
+    /// Publishes chunk and returns the account it was stored on
+    fn publish(&mut self, s: &chunk::Chunk) -> Result<AccountId, ProviderError>;
+    /// Publishes several chunks at once and returns their accounts in order,
+    /// backends with batch APIs should override it
+    fn publish_batch(&mut self, cs: &[chunk::Chunk]) -> Result<Vec<AccountId>, ProviderError> {
+        cs.iter().map(|c| self.publish(c)).collect()
+    }
+    fn receive(&mut self, c: &chunk::Stored) -> Result<chunk::Data, ProviderError>;
+    /// Receives several chunks at once in the requested order
+    fn receive_batch(&mut self, cs: &[chunk::Stored]) -> Result<Vec<chunk::Data>, ProviderError> {
+        cs.iter().map(|c| self.receive(c)).collect()
+    }
+    fn delete(&mut self, cs: &[chunk::Stored]) -> Result<(), ProviderError>;
+}
+
+impl<P: Provider + ?Sized> Provider for Box<P> {
+    fn publish(&mut self, s: &chunk::Chunk) -> Result<AccountId, ProviderError> {
+        (**self).publish(s)
+    }
+
+    fn publish_batch(&mut self, cs: &[chunk::Chunk]) -> Result<Vec<AccountId>, ProviderError> {
+        (**self).publish_batch(cs)
+    }
+
+    fn receive(&mut self, c: &chunk::Stored) -> Result<chunk::Data, ProviderError> {
+        (**self).receive(c)
+    }
+
+    fn receive_batch(&mut self, cs: &[chunk::Stored]) -> Result<Vec<chunk::Data>, ProviderError> {
+        (**self).receive_batch(cs)
+    }
+
+    fn delete(&mut self, cs: &[chunk::Stored]) -> Result<(), ProviderError> {
+        (**self).delete(cs)
+    }
+}
+
+/// Identifies the account by its account string, never 0
+pub fn account_id(account: &str) -> AccountId {
+    let mut head = [0u8; 4];
+    head.copy_from_slice(&crypto::hash(account.as_bytes()).hash()[..4]);
+    u32::from_be_bytes(head).max(1)
+}
+
+/// Opens the provider described by an account string
+///
+/// `dir:<path>` is a local directory, `s3:<endpoint>/<bucket>` is a bucket
+/// of S3 compatible storage, `dav:<url>` is a WebDAV collection,
+/// `sftp://<user>@<host>/<dir>` is a directory on SSH host, `gdrive:<token>`
+/// is a Google Drive account, `onedrive:<token>` is a OneDrive account,
+/// anything else is a Dropbox token.
+pub fn open(account: String) -> Result<Box<dyn Provider + Send>, ProviderError> {
+    Ok(if let Some(root) = account.strip_prefix("dir:") {
+        Box::new(directory::Directory::new(root)?)
+    } else if let Some(url) = account.strip_prefix("s3:") {
+        let slash = url
+            .rfind('/')
+            .ok_or_else(|| ProviderError::Invalid(format!("no bucket in S3 url {}", url)))?;
+        let (endpoint, bucket) = url.split_at(slash);
+        Box::new(s3::S3::from_env(endpoint, &bucket[1..])?)
+    } else if let Some(url) = account.strip_prefix("dav:") {
+        Box::new(webdav::WebDav::from_url(url)?)
+    } else if account.starts_with("sftp:") {
+        open_sftp(&account)?
+    } else if let Some(token) = account.strip_prefix("gdrive:") {
+        Box::new(gdrive::GDrive::new(token.to_owned()))
+    } else if let Some(token) = account.strip_prefix("onedrive:") {
+        Box::new(onedrive::OneDrive::new(token.to_owned()))
+    } else {
+        Box::new(dropbox::Dropbox::new(account))
+    })
+}
+
+#[cfg(feature = "sftp")]
+fn open_sftp(url: &str) -> Result<Box<dyn Provider + Send>, ProviderError> {
+    Ok(Box::new(sftp::Sftp::from_url(url)?))
+}
+
+#[cfg(not(feature = "sftp"))]
+fn open_sftp(url: &str) -> Result<Box<dyn Provider + Send>, ProviderError> {
+    Err(ProviderError::Invalid(format!(
+        "{} needs the sftp feature",
+        url
+    )))
 }
@@ -0,0 +1,115 @@
+use std::io::Read;
+
+use log::*;
+use reqwest;
+use reqwest::header::CONTENT_TYPE;
+
+use crate::chunk;
+use crate::crypto::Hash;
+use crate::remote::{check, deleted, AccountId, Provider, ProviderError};
+
+const APPROOT: &str = "https://graph.microsoft.com/v1.0/me/drive/special/approot";
+
+/// Client of Microsoft Graph drive API, chunks are kept in the app folder
+#[derive(Debug)]
+pub struct OneDrive {
+    token: String,
+    approot: String,
+    client: reqwest::Client,
+}
+
+impl OneDrive {
+    pub fn new(token: String) -> OneDrive {
+        OneDrive::with_approot(token, APPROOT.to_owned())
+    }
+
+    pub fn with_approot(token: String, approot: String) -> OneDrive {
+        OneDrive {
+            token,
+            approot,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    fn item(&self, h: &Hash) -> String {
+        format!("{}:/{}", self.approot, h)
+    }
+}
+
+impl Provider for OneDrive {
+    fn publish(&mut self, s: &chunk::Chunk) -> Result<AccountId, ProviderError> {
+        let res = self
+            .client
+            .put(&format!("{}:/content", self.item(&s.name)))
+            .bearer_auth(&self.token)
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .body(s.chunk.to_vec())
+            .send()?;
+        debug!("{:?}", res);
+        check(res)?;
+        Ok(0)
+    }
+
+    fn receive(&mut self, c: &chunk::Stored) -> Result<chunk::Data, ProviderError> {
+        let res = self
+            .client
+            .get(&format!("{}:/content", self.item(&c.name)))
+            .bearer_auth(&self.token)
+            .send()?;
+        debug!("{:?}", res);
+        let mut r = Vec::new();
+        check(res)?.read_to_end(&mut r)?;
+        Ok(r)
+    }
+
+    fn delete(&mut self, cs: &[chunk::Stored]) -> Result<(), ProviderError> {
+        for c in cs {
+            let res = self
+                .client
+                .delete(&self.item(&c.name))
+                .bearer_auth(&self.token)
+                .send()?;
+            debug!("{:?}", res);
+            deleted(check(res))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::OneDrive;
+    use crate::chunk;
+    use crate::remote::stub::Stub;
+    use crate::remote::Provider;
+    use crate::testing::publish_receive;
+
+    #[test]
+    fn publish_receive_delete() {
+        let stub = Stub::default();
+        let approot = format!("{}/approot", stub.serve());
+        let mut drive = OneDrive::with_approot("token".to_owned(), approot);
+        let stored = publish_receive(&mut drive, &[4u8; chunk::CHUNK_SIZE]);
+        drive.delete(&[stored.clone()]).unwrap();
+        assert!(stub.objects.lock().unwrap().is_empty());
+        drive.delete(&[stored.clone()]).unwrap();
+
+        let requests = stub.requests.lock().unwrap();
+        let item = format!("/approot:/{}", stored.hash);
+        assert_eq!(
+            requests
+                .iter()
+                .map(|r| (r.method.as_str(), r.path.as_str()))
+                .collect::<Vec<_>>(),
+            [
+                ("PUT", format!("{}:/content", item).as_str()),
+                ("GET", format!("{}:/content", item).as_str()),
+                ("DELETE", item.as_str()),
+                ("DELETE", item.as_str()),
+            ]
+        );
+        assert!(requests
+            .iter()
+            .all(|r| r.headers["authorization"] == "Bearer token"));
+    }
+}
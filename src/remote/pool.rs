@@ -0,0 +1,353 @@
+use crossbeam_utils::thread;
+use log::*;
+
+use crate::chunk;
+use crate::crypto::Hash;
+use crate::remote::{AccountId, Provider, ProviderError};
+
+/// Set of providers that behaves as a single one
+///
+/// Every chunk is placed on exactly one account chosen from the chunk hash,
+/// the id of that account is reported back as the chunk account, so chunks
+/// are found whatever order the accounts are given in. Every account is
+/// opened by several providers, batches are split by account and the part of
+/// an account is split between its providers, up to `jobs` of the parts are
+/// transferred concurrently, each in its own thread.
+pub struct Pool<P: Provider + Send> {
+    ids: Vec<AccountId>,
+    providers: Vec<Vec<P>>,
+    jobs: usize,
+}
+
+impl<P: Provider + Send> Pool<P> {
+    pub fn new(accounts: Vec<(AccountId, Vec<P>)>, jobs: usize) -> Pool<P> {
+        assert!(!accounts.is_empty(), "Pool requires at least one provider");
+        assert!(
+            accounts.iter().all(|(_, ps)| !ps.is_empty()),
+            "Pool requires a provider of every account"
+        );
+        assert!(jobs > 0, "Pool requires at least one job");
+        let (ids, providers) = accounts.into_iter().unzip();
+        Pool {
+            ids,
+            providers,
+            jobs,
+        }
+    }
+
+    fn place(&self, h: &Hash) -> usize {
+        let mut head = [0u8; 8];
+        head.copy_from_slice(&h.hash()[..8]);
+        (u64::from_be_bytes(head) % self.providers.len() as u64) as usize
+    }
+
+    /// Position of the account in the pool, chunks of account 0 are on the
+    /// first one
+    fn position(&self, account: AccountId) -> Result<usize, ProviderError> {
+        if account == 0 {
+            return Ok(0);
+        }
+        self.ids
+            .iter()
+            .position(|id| *id == account)
+            .ok_or_else(|| {
+                warn!("Account {:08x} is out of pool", account);
+                ProviderError::UnknownAccount(account)
+            })
+    }
+
+    /// Splits stored chunks by their accounts, keeping positions of the
+    /// chunks in every group
+    fn group(
+        &self,
+        cs: &[chunk::Stored],
+    ) -> Result<Vec<Vec<(usize, chunk::Stored)>>, ProviderError> {
+        let mut groups = vec![Vec::new(); self.providers.len()];
+        for (i, c) in cs.iter().enumerate() {
+            groups[self.position(c.account)?].push((i, c.clone()));
+        }
+        Ok(groups)
+    }
+
+    /// Splits every group evenly between the providers of its account and
+    /// applies `f` to the parts, `jobs` parts at a time, returns results of
+    /// the parts in no particular order
+    fn run<T, R, F>(&mut self, groups: Vec<Vec<T>>, f: F) -> Result<Vec<R>, ProviderError>
+    where
+        T: Sync,
+        R: Send,
+        F: Fn(&mut P, &[T]) -> Result<R, ProviderError> + Sync,
+    {
+        let f = &f;
+        let mut work = Vec::new();
+        for (providers, g) in self.providers.iter_mut().zip(&groups) {
+            if g.is_empty() {
+                continue;
+            }
+            let part = g.len().div_ceil(providers.len());
+            work.extend(providers.iter_mut().zip(g.chunks(part)));
+        }
+        let mut r = Vec::with_capacity(work.len());
+        for wave in work.chunks_mut(self.jobs) {
+            let results = thread::scope(|s| {
+                let handles: Vec<_> = wave
+                    .iter_mut()
+                    .map(|(p, g)| s.spawn(move |_| f(p, g)))
+                    .collect();
+                trace!("{} transfers in parallel", handles.len());
+                handles
+                    .into_iter()
+                    .map(|h| h.join().expect("Transfer thread panicked"))
+                    .collect::<Vec<_>>()
+            })
+            .expect("Transfer thread panicked");
+            for result in results {
+                r.push(result?);
+            }
+        }
+        Ok(r)
+    }
+}
+
+impl<P: Provider + Send> Provider for Pool<P> {
+    fn publish(&mut self, s: &chunk::Chunk) -> Result<AccountId, ProviderError> {
+        let i = self.place(&s.hash);
+        trace!("publish {} to {:08x}", s.hash, self.ids[i]);
+        self.providers[i][0].publish(s)?;
+        Ok(self.ids[i])
+    }
+
+    fn publish_batch(&mut self, cs: &[chunk::Chunk]) -> Result<Vec<AccountId>, ProviderError> {
+        let mut groups = vec![Vec::new(); self.providers.len()];
+        let placement: Vec<_> = cs.iter().map(|c| self.place(&c.hash)).collect();
+        for (c, i) in cs.iter().zip(&placement) {
+            groups[*i].push(c.clone());
+        }
+        self.run(groups, |p, g| p.publish_batch(g))?;
+        Ok(placement.into_iter().map(|i| self.ids[i]).collect())
+    }
+
+    fn receive(&mut self, c: &chunk::Stored) -> Result<chunk::Data, ProviderError> {
+        trace!("receive {} from {:08x}", c.hash, c.account);
+        let i = self.position(c.account)?;
+        self.providers[i][0].receive(c)
+    }
+
+    fn receive_batch(&mut self, cs: &[chunk::Stored]) -> Result<Vec<chunk::Data>, ProviderError> {
+        let groups = self.group(cs)?;
+        let received = self.run(groups, |p, g| {
+            let stored: Vec<_> = g.iter().map(|(_, c)| c.clone()).collect();
+            let data = p.receive_batch(&stored)?;
+            Ok(g.iter().map(|(i, _)| *i).zip(data).collect::<Vec<_>>())
+        })?;
+        let mut r = vec![Vec::new(); cs.len()];
+        for (i, d) in received.into_iter().flatten() {
+            r[i] = d;
+        }
+        Ok(r)
+    }
+
+    fn delete(&mut self, cs: &[chunk::Stored]) -> Result<(), ProviderError> {
+        let groups = self.group(cs)?;
+        self.run(groups, |p, g| {
+            let g: Vec<_> = g.iter().map(|(_, c)| c.clone()).collect();
+            p.delete(&g)
+        })
+        .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use super::Pool;
+    use crate::chunk;
+    use crate::crypto;
+    use crate::remote::{AccountId, Provider, ProviderError};
+
+    /// Provider of an account, its clones are other providers of the same one
+    #[derive(Default, Clone)]
+    struct Mock {
+        chunks: Arc<Mutex<HashMap<String, chunk::Data>>>,
+        /// Number of publishes in progress and the highest one seen
+        publishing: Arc<(AtomicUsize, AtomicUsize)>,
+    }
+
+    impl Mock {
+        fn len(&self) -> usize {
+            self.chunks.lock().unwrap().len()
+        }
+    }
+
+    impl Provider for Mock {
+        fn publish(&mut self, s: &chunk::Chunk) -> Result<AccountId, ProviderError> {
+            let (now, peak) = &*self.publishing;
+            peak.fetch_max(now.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(1));
+            self.chunks
+                .lock()
+                .unwrap()
+                .insert(s.hash.to_string(), s.chunk.clone());
+            now.fetch_sub(1, Ordering::SeqCst);
+            Ok(0)
+        }
+
+        fn receive(&mut self, c: &chunk::Stored) -> Result<chunk::Data, ProviderError> {
+            self.chunks
+                .lock()
+                .unwrap()
+                .get(&c.hash.to_string())
+                .cloned()
+                .ok_or(ProviderError::NotFound)
+        }
+
+        fn delete(&mut self, cs: &[chunk::Stored]) -> Result<(), ProviderError> {
+            let mut chunks = self.chunks.lock().unwrap();
+            for c in cs {
+                chunks
+                    .remove(&c.hash.to_string())
+                    .ok_or(ProviderError::NotFound)?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Pool of `n` mock accounts with ids 10, 20 and so on, each opened
+    /// `jobs` times
+    fn mocks(n: u32, jobs: usize) -> Pool<Mock> {
+        let accounts = (1..=n)
+            .map(|i| (i * 10, vec![Mock::default(); jobs]))
+            .collect();
+        Pool::new(accounts, jobs)
+    }
+
+    fn chunk(i: u8) -> chunk::Chunk {
+        let block = vec![i; chunk::CHUNK_SIZE];
+        chunk::Chunk {
+            hash: crypto::hash(&block),
+            name: crypto::hash(&block),
+            chunk: block,
+            idx: u64::from(i),
+            codec: chunk::Codec::None,
+        }
+    }
+
+    fn publish_all(pool: &mut Pool<Mock>, chunks: &[chunk::Chunk]) -> Vec<chunk::Stored> {
+        chunks
+            .iter()
+            .map(|c| chunk::Stored {
+                hash: c.hash.clone(),
+                name: c.name.clone(),
+                account: pool.publish(c).unwrap(),
+                len: c.chunk.len(),
+                codec: chunk::Codec::None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn spread_and_route() {
+        let mut pool = mocks(3, 1);
+        let chunks: Vec<_> = (0..32).map(chunk).collect();
+        let stored = publish_all(&mut pool, &chunks);
+
+        let sizes: Vec<_> = pool.providers.iter().map(|p| p[0].len()).collect();
+        assert_eq!(sizes.iter().sum::<usize>(), chunks.len());
+        assert!(sizes.iter().all(|n| *n > 0));
+
+        chunks.iter().zip(&stored).for_each(|(c, s)| {
+            assert!(pool.providers[pool.position(s.account).unwrap()][0]
+                .chunks
+                .lock()
+                .unwrap()
+                .contains_key(&c.hash.to_string()));
+            assert_eq!(&pool.receive(s).unwrap()[..], &c.chunk[..]);
+        });
+    }
+
+    #[test]
+    fn route_by_account() {
+        let mut pool = mocks(3, 1);
+        let chunks: Vec<_> = (0..32).map(chunk).collect();
+        let mut stored = publish_all(&mut pool, &chunks);
+        assert!(stored.iter().all(|s| [10, 20, 30].contains(&s.account)));
+
+        let accounts: Vec<_> = pool.ids.into_iter().zip(pool.providers).rev().collect();
+        let mut pool = Pool::new(accounts, 1);
+        let received = pool.receive_batch(&stored).unwrap();
+        chunks
+            .iter()
+            .zip(&received)
+            .for_each(|(c, r)| assert_eq!(&c.chunk[..], &r[..]));
+
+        let first = stored.iter().position(|s| s.account == 30).unwrap();
+        stored[first].account = 0;
+        assert_eq!(
+            &pool.receive(&stored[first]).unwrap()[..],
+            &chunks[first].chunk[..]
+        );
+        stored[first].account = 40;
+        match pool.delete(&stored) {
+            Err(ProviderError::UnknownAccount(40)) => {}
+            r => panic!("Unexpected {:?}", r),
+        }
+        assert_eq!(
+            pool.providers.iter().map(|p| p[0].len()).sum::<usize>(),
+            chunks.len()
+        );
+    }
+
+    #[test]
+    fn publish_batch() {
+        let mut pool = mocks(3, 1);
+        let chunks: Vec<_> = (0..32).map(chunk).collect();
+        let accounts = pool.publish_batch(&chunks).unwrap();
+        assert_eq!(accounts.len(), chunks.len());
+        chunks.iter().zip(accounts).for_each(|(c, a)| {
+            let s = chunk::Stored {
+                hash: c.hash.clone(),
+                name: c.name.clone(),
+                account: a,
+                len: c.chunk.len(),
+                codec: chunk::Codec::None,
+            };
+            assert_eq!(&pool.receive(&s).unwrap()[..], &c.chunk[..]);
+        });
+    }
+
+    #[test]
+    fn receive_batch_in_order() {
+        let mut pool = mocks(3, 2);
+        let chunks: Vec<_> = (0..32).map(chunk).collect();
+        let stored = publish_all(&mut pool, &chunks);
+        let received = pool.receive_batch(&stored).unwrap();
+        assert_eq!(received.len(), chunks.len());
+        chunks
+            .iter()
+            .zip(&received)
+            .for_each(|(c, r)| assert_eq!(&c.chunk[..], &r[..]));
+    }
+
+    #[test]
+    fn delete_from_owners() {
+        let mut pool = mocks(3, 3);
+        let chunks: Vec<_> = (0..32).map(chunk).collect();
+        let stored = publish_all(&mut pool, &chunks);
+        pool.delete(&stored).unwrap();
+        assert!(pool.providers.iter().all(|p| p[0].len() == 0));
+    }
+
+    #[test]
+    fn transfer_concurrently_on_account() {
+        let mut pool = mocks(1, 4);
+        let chunks: Vec<_> = (0..32).map(chunk).collect();
+        assert_eq!(pool.publish_batch(&chunks).unwrap(), vec![10; chunks.len()]);
+        assert_eq!(pool.providers[0][0].len(), chunks.len());
+        let peak = pool.providers[0][0].publishing.1.load(Ordering::SeqCst);
+        assert!(1 < peak && peak <= 4, "{} publishes at once", peak);
+    }
+}
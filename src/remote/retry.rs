@@ -0,0 +1,364 @@
+use std::cmp::{max, min};
+use std::thread;
+use std::time::Duration;
+
+use log::*;
+
+use crate::chunk;
+use crate::crypto::Hash;
+use crate::remote::{AccountId, Provider, ProviderError};
+
+#[derive(Debug, Clone)]
+pub struct Policy {
+    /// Total number of tries of a single operation
+    pub attempts: u32,
+    /// Delay before the first retry, doubled on every next one
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            attempts: 5,
+            backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Policy {
+    /// Delay before the retry following the failed `attempt` (counting from
+    /// zero), `None` if the error is not transient
+    fn delay(&self, attempt: u32, e: &ProviderError) -> Option<Duration> {
+        match e {
+            ProviderError::RateLimited(Some(after)) => Some(*after),
+            ProviderError::RateLimited(None)
+            | ProviderError::Network(_)
+            | ProviderError::Mismatch(_) => {
+                let factor = 1u32.checked_shl(attempt).unwrap_or(u32::MAX);
+                Some(min(
+                    self.backoff.checked_mul(factor).unwrap_or(self.max_backoff),
+                    self.max_backoff,
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Repeats failed operations of the wrapped provider according to the policy
+pub struct Retry<P: Provider> {
+    inner: P,
+    policy: Policy,
+}
+
+impl<P: Provider> Retry<P> {
+    pub fn new(inner: P, policy: Policy) -> Retry<P> {
+        Retry { inner, policy }
+    }
+
+    fn run<T, F>(&mut self, op: &str, mut f: F) -> Result<T, ProviderError>
+    where
+        F: FnMut(&mut P) -> Result<T, ProviderError>,
+    {
+        let mut attempt = 0;
+        loop {
+            let e = match f(&mut self.inner) {
+                Ok(r) => return Ok(r),
+                Err(e) => e,
+            };
+            attempt += 1;
+            match self.policy.delay(attempt - 1, &e) {
+                Some(delay) if attempt < self.policy.attempts => {
+                    warn!("{} failed ({}), retry in {:?}", op, e, delay);
+                    thread::sleep(delay);
+                }
+                _ => return Err(e),
+            }
+        }
+    }
+
+    /// Like `run`, but when the batch fails partially only its entries
+    /// failed with transient errors are repeated, the rest are reported
+    /// after the retries
+    fn run_batch<T, R, F>(
+        &mut self,
+        op: &str,
+        items: &[T],
+        hash: fn(&T) -> &Hash,
+        mut f: F,
+    ) -> Result<R, ProviderError>
+    where
+        T: Clone,
+        F: FnMut(&mut P, &[T]) -> Result<R, ProviderError>,
+    {
+        let mut items = items.to_vec();
+        let mut failed = Vec::new();
+        let mut attempt = 0;
+        loop {
+            let e = match f(&mut self.inner, &items) {
+                Ok(_) if !failed.is_empty() => return Err(ProviderError::Partial(failed)),
+                Ok(r) => return Ok(r),
+                Err(e) => e,
+            };
+            attempt += 1;
+            let delay = match e {
+                ProviderError::Partial(es) => {
+                    let mut delay = Duration::default();
+                    let mut transient = Vec::new();
+                    for (h, e) in es {
+                        match self.policy.delay(attempt - 1, &e) {
+                            Some(d) if attempt < self.policy.attempts => {
+                                delay = max(delay, d);
+                                transient.push(h);
+                            }
+                            _ => failed.push((h, e)),
+                        }
+                    }
+                    if transient.is_empty() {
+                        return Err(ProviderError::Partial(failed));
+                    }
+                    items.retain(|c| transient.contains(hash(c)));
+                    warn!(
+                        "{} failed for {} chunks, retry in {:?}",
+                        op,
+                        items.len(),
+                        delay
+                    );
+                    delay
+                }
+                e => match self.policy.delay(attempt - 1, &e) {
+                    Some(delay) if attempt < self.policy.attempts => {
+                        warn!("{} failed ({}), retry in {:?}", op, e, delay);
+                        delay
+                    }
+                    _ => return Err(e),
+                },
+            };
+            thread::sleep(delay);
+        }
+    }
+}
+
+impl<P: Provider> Provider for Retry<P> {
+    fn publish(&mut self, s: &chunk::Chunk) -> Result<AccountId, ProviderError> {
+        self.run("publish", |p| p.publish(s))
+    }
+
+    /// The wrapped provider is a single account, so chunks published before
+    /// a retry are on the account the retried ones are reported on
+    fn publish_batch(&mut self, cs: &[chunk::Chunk]) -> Result<Vec<AccountId>, ProviderError> {
+        let accounts = self.run_batch(
+            "publish_batch",
+            cs,
+            |c| &c.hash,
+            |p, cs| p.publish_batch(cs),
+        )?;
+        if accounts.len() == cs.len() {
+            Ok(accounts)
+        } else {
+            Ok(vec![accounts[0]; cs.len()])
+        }
+    }
+
+    /// Received chunk is verified, so one damaged in transfer is requested
+    /// again, a chunk which fails decryption is not as it would fail again
+    fn receive(&mut self, c: &chunk::Stored) -> Result<chunk::Data, ProviderError> {
+        self.run("receive", |p| {
+            let data = p.receive(c)?;
+            c.check(&data)?;
+            Ok(data)
+        })
+    }
+
+    fn delete(&mut self, cs: &[chunk::Stored]) -> Result<(), ProviderError> {
+        self.run_batch("delete", cs, |c| &c.hash, |p, cs| p.delete(cs))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{Policy, Retry};
+    use crate::chunk;
+    use crate::crypto;
+    use crate::remote::{AccountId, Provider, ProviderError};
+    use crate::testing::chunk_of;
+
+    /// Fails with errors from the list before succeeding, then receives
+    /// garbage the given number of times before the chunk of `stored`
+    struct Flaky {
+        errors: Vec<ProviderError>,
+        garbage: usize,
+        calls: usize,
+        /// Lengths of the batches requested
+        batches: Vec<usize>,
+    }
+
+    impl Flaky {
+        fn attempt(&mut self) -> Result<(), ProviderError> {
+            self.calls += 1;
+            match self.errors.pop() {
+                Some(e) => Err(e),
+                None => Ok(()),
+            }
+        }
+    }
+
+    impl Provider for Flaky {
+        fn publish(&mut self, _: &chunk::Chunk) -> Result<AccountId, ProviderError> {
+            self.attempt().map(|_| 0)
+        }
+
+        fn publish_batch(&mut self, cs: &[chunk::Chunk]) -> Result<Vec<AccountId>, ProviderError> {
+            self.batches.push(cs.len());
+            self.attempt().map(|_| vec![0; cs.len()])
+        }
+
+        fn receive(&mut self, _: &chunk::Stored) -> Result<chunk::Data, ProviderError> {
+            self.attempt()?;
+            if self.garbage > 0 {
+                self.garbage -= 1;
+                Ok(vec![0])
+            } else {
+                Ok(vec![1])
+            }
+        }
+
+        fn delete(&mut self, cs: &[chunk::Stored]) -> Result<(), ProviderError> {
+            self.batches.push(cs.len());
+            self.attempt()
+        }
+    }
+
+    fn retry(errors: Vec<ProviderError>) -> Retry<Flaky> {
+        let policy = Policy {
+            attempts: 3,
+            backoff: Duration::from_millis(0),
+            max_backoff: Duration::from_millis(0),
+        };
+        Retry::new(
+            Flaky {
+                errors,
+                garbage: 0,
+                calls: 0,
+                batches: Vec::new(),
+            },
+            policy,
+        )
+    }
+
+    fn stored() -> chunk::Stored {
+        chunk::Stored {
+            hash: crypto::hash(&[1]),
+            name: crypto::hash(&[1]),
+            account: 0,
+            len: 1,
+            codec: chunk::Codec::None,
+        }
+    }
+
+    #[test]
+    fn retry_transient() {
+        let mut p = retry(vec![
+            ProviderError::Network("reset".to_owned()),
+            ProviderError::RateLimited(Some(Duration::from_millis(1))),
+        ]);
+        assert!(p.receive(&stored()).is_ok());
+        assert_eq!(p.inner.calls, 3);
+    }
+
+    #[test]
+    fn retry_mismatch() {
+        let mut p = retry(Vec::new());
+        p.inner.garbage = 2;
+        assert_eq!(p.receive(&stored()).unwrap(), vec![1]);
+        assert_eq!(p.inner.calls, 3);
+        p.inner.garbage = 3;
+        match p.receive(&stored()) {
+            Err(ProviderError::Mismatch(ref h)) if *h == stored().hash => {}
+            r => panic!("Unexpected {:?}", r),
+        }
+        assert_eq!(p.inner.calls, 6);
+    }
+
+    #[test]
+    fn retry_failed_entries() {
+        let cs: Vec<_> = (0..3).map(|i| chunk_of(&[i])).collect();
+        let network = || ProviderError::Network("reset".to_owned());
+        let mut p = retry(vec![ProviderError::Partial(vec![(
+            cs[1].hash.clone(),
+            network(),
+        )])]);
+        assert_eq!(p.publish_batch(&cs).unwrap(), vec![0; 3]);
+        assert_eq!(p.inner.batches, [3, 1]);
+
+        let stored: Vec<_> = cs
+            .iter()
+            .map(|c| chunk::Stored {
+                hash: c.hash.clone(),
+                name: c.name.clone(),
+                account: 0,
+                len: c.chunk.len(),
+                codec: c.codec,
+            })
+            .collect();
+        // the failure which is not transient is reported after the retries
+        let mut p = retry(vec![
+            ProviderError::Partial(vec![(cs[2].hash.clone(), network())]),
+            ProviderError::Partial(vec![
+                (cs[0].hash.clone(), ProviderError::Auth),
+                (cs[2].hash.clone(), network()),
+            ]),
+        ]);
+        match p.delete(&stored) {
+            Err(ProviderError::Partial(ref failed)) => match failed.as_slice() {
+                [(h, ProviderError::Auth)] if *h == cs[0].hash => {}
+                r => panic!("Unexpected {:?}", r),
+            },
+            r => panic!("Unexpected {:?}", r),
+        }
+        assert_eq!(p.inner.batches, [3, 1, 1]);
+    }
+
+    #[test]
+    fn give_up() {
+        let mut p = retry((0..3).map(|_| ProviderError::RateLimited(None)).collect());
+        match p.delete(&[stored()]) {
+            Err(ProviderError::RateLimited(None)) => {}
+            r => panic!("Unexpected {:?}", r),
+        }
+        assert_eq!(p.inner.calls, 3);
+    }
+
+    #[test]
+    fn fail_fast() {
+        let mut p = retry(vec![ProviderError::Auth]);
+        match p.delete(&[stored()]) {
+            Err(ProviderError::Auth) => {}
+            r => panic!("Unexpected {:?}", r),
+        }
+        assert_eq!(p.inner.calls, 1);
+    }
+
+    #[test]
+    fn backoff() {
+        let policy = Policy {
+            attempts: 10,
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5),
+        };
+        let network = ProviderError::Network(String::new());
+        let delays: Vec<_> = (0..5)
+            .map(|i| policy.delay(i, &network).unwrap().as_secs())
+            .collect();
+        assert_eq!(delays, [1, 2, 4, 5, 5]);
+        let limited = ProviderError::RateLimited(Some(Duration::from_secs(42)));
+        assert_eq!(policy.delay(0, &limited), Some(Duration::from_secs(42)));
+        let corrupted = ProviderError::Corrupted(crypto::hash(&[1]));
+        assert_eq!(policy.delay(0, &corrupted), None);
+        assert_eq!(policy.delay(0, &ProviderError::NotFound), None);
+    }
+}
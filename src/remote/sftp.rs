@@ -0,0 +1,129 @@
+use std::env;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+
+use log::*;
+use reqwest::Url;
+use ssh2::Session;
+
+use crate::chunk;
+use crate::crypto::Hash;
+use crate::remote::{deleted, AccountId, Provider, ProviderError};
+
+/// Stores chunks as files named by their remote names in a directory of SSH host
+pub struct Sftp {
+    sftp: ssh2::Sftp,
+    dir: PathBuf,
+    // sftp channel is valid only while the session is alive
+    _session: Session,
+}
+
+impl Sftp {
+    /// Connects to `host:port` and authenticates with the private key
+    pub fn new(
+        host: &str,
+        port: u16,
+        user: &str,
+        key: &Path,
+        passphrase: Option<&str>,
+        dir: &Path,
+    ) -> Result<Sftp, ProviderError> {
+        let tcp = TcpStream::connect((host, port))?;
+        let mut session = Session::new().map_err(io::Error::from)?;
+        session.set_tcp_stream(tcp);
+        session.handshake().map_err(io::Error::from)?;
+        session
+            .userauth_pubkey_file(user, None, key, passphrase)
+            .map_err(|_| ProviderError::Auth)?;
+        let sftp = session.sftp().map_err(io::Error::from)?;
+        if sftp.stat(dir).is_err() {
+            sftp.mkdir(dir, 0o700).map_err(io::Error::from)?;
+        }
+        Ok(Sftp {
+            sftp,
+            dir: dir.to_path_buf(),
+            _session: session,
+        })
+    }
+
+    /// Parses `sftp://user@host[:port]/dir`, the private key is taken from
+    /// `SFTP_KEY` (`~/.ssh/id_rsa` by default) and optionally decrypted with
+    /// `SFTP_KEY_PASSPHRASE`
+    pub fn from_url(url: &str) -> Result<Sftp, ProviderError> {
+        let invalid = |e: &str| ProviderError::Invalid(format!("SFTP url {}: {}", url, e));
+        let url = Url::parse(url).map_err(|e| invalid(&e.to_string()))?;
+        let key = match env::var("SFTP_KEY") {
+            Ok(key) => PathBuf::from(key),
+            Err(_) => PathBuf::from(env::var("HOME").map_err(|_| invalid("HOME is not set"))?)
+                .join(".ssh/id_rsa"),
+        };
+        let user = match url.username() {
+            "" => env::var("USER").map_err(|_| invalid("no user"))?,
+            user => user.to_owned(),
+        };
+        Sftp::new(
+            url.host_str().ok_or_else(|| invalid("no host"))?,
+            url.port().unwrap_or(22),
+            &user,
+            &key,
+            env::var("SFTP_KEY_PASSPHRASE").ok().as_deref(),
+            Path::new(url.path()),
+        )
+    }
+
+    fn path(&self, h: &Hash) -> PathBuf {
+        self.dir.join(h.to_string())
+    }
+}
+
+impl Provider for Sftp {
+    fn publish(&mut self, s: &chunk::Chunk) -> Result<AccountId, ProviderError> {
+        let path = self.path(&s.name);
+        trace!("publish {:?}", path);
+        self.sftp
+            .create(&path)
+            .map_err(io::Error::from)?
+            .write_all(&s.chunk)?;
+        Ok(0)
+    }
+
+    fn receive(&mut self, c: &chunk::Stored) -> Result<chunk::Data, ProviderError> {
+        let path = self.path(&c.name);
+        trace!("receive {:?}", path);
+        let mut r = Vec::new();
+        self.sftp
+            .open(&path)
+            .map_err(io::Error::from)?
+            .read_to_end(&mut r)?;
+        Ok(r)
+    }
+
+    fn delete(&mut self, cs: &[chunk::Stored]) -> Result<(), ProviderError> {
+        for path in cs.iter().map(|c| self.path(&c.name)) {
+            trace!("delete {:?}", path);
+            let unlinked = self.sftp.unlink(&path).map_err(io::Error::from);
+            deleted(unlinked.map_err(ProviderError::from))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Sftp;
+    use crate::chunk;
+    use crate::remote::Provider;
+    use crate::testing::publish_receive;
+
+    #[test]
+    #[ignore]
+    fn publish_receive_delete() {
+        // e.g. sftp://user@localhost/tmp/cloud-stash
+        let url = std::env::var("SFTP_TEST_URL").expect("SFTP_TEST_URL is not set");
+        let mut sftp = Sftp::from_url(&url).unwrap();
+        let stored = publish_receive(&mut sftp, &[9u8; chunk::CHUNK_SIZE]);
+        sftp.delete(&[stored.clone()]).unwrap();
+        assert!(sftp.sftp.stat(&sftp.path(&stored.hash)).is_err());
+    }
+}
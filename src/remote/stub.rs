@@ -0,0 +1,181 @@
+//! In-process HTTP object store used to test HTTP based providers
+
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use reqwest::Url;
+use serde_json::{json, Value};
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+/// Stores bodies of `PUT` requests by path, serves them on `GET` and drops
+/// them on `DELETE` or on S3 `POST ?delete` listing their keys; every request
+/// is recorded for later inspection. OneDrive `<item>:/content` paths address
+/// the same object as `<item>`. Drive multipart uploads create objects under
+/// `<files>/<id>`, which are listed by name with `<files>?q=name = '<name>'`.
+#[derive(Clone, Default)]
+pub struct Stub {
+    pub objects: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    pub requests: Arc<Mutex<Vec<Request>>>,
+    /// Paths of objects which are refused to be deleted
+    pub denied: Arc<Mutex<HashSet<String>>>,
+    /// Names of Drive files by their paths
+    pub names: Arc<Mutex<HashMap<String, String>>>,
+}
+
+/// Offset of the needle in the data starting from `from`, the data length
+/// if there is none
+fn find(data: &[u8], needle: &[u8], from: usize) -> usize {
+    data[from..]
+        .windows(needle.len())
+        .position(|w| w == needle)
+        .map_or(data.len(), |i| from + i)
+}
+
+impl Stub {
+    /// Starts serving on a random local port and returns the base url
+    pub fn serve(&self) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stub = self.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                stub.handle(stream.unwrap());
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    fn handle(&self, stream: TcpStream) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let mut parts = line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_string();
+        let path = parts.next().unwrap_or_default().to_string();
+
+        let mut headers = HashMap::new();
+        loop {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let mut kv = header.splitn(2, ':');
+            headers.insert(
+                kv.next().unwrap().trim().to_lowercase(),
+                kv.next().unwrap_or_default().trim().to_string(),
+            );
+        }
+        let len = headers
+            .get("content-length")
+            .map(|l| l.parse().unwrap())
+            .unwrap_or(0);
+        let mut body = vec![0u8; len];
+        reader.read_exact(&mut body).unwrap();
+
+        let mut split = path.splitn(2, '?');
+        let key = split.next().unwrap();
+        let key = key.trim_end_matches(":/content").to_string();
+        let query = split.next().unwrap_or_default();
+        let mut objects = self.objects.lock().unwrap();
+        let (status, response) = match method.as_str() {
+            "POST" if key.ends_with("/upload/drive/v3/files") => {
+                // metadata and media parts follow their headers, the media
+                // one ends with the closing boundary
+                let boundary = headers["content-type"].rsplit('=').next().unwrap();
+                let metadata = find(&body, b"\r\n\r\n", 0) + 4;
+                let media = find(&body, b"\r\n\r\n", metadata) + 4;
+                let end = body.len() - format!("\r\n--{}--", boundary).len();
+                let metadata = &body[metadata..find(&body, b"\r\n--", metadata)];
+                let name = serde_json::from_slice::<Value>(metadata).unwrap()["name"]
+                    .as_str()
+                    .unwrap()
+                    .to_owned();
+                let id = format!("file{}", self.requests.lock().unwrap().len());
+                let path = format!("{}/{}", key.replace("/upload", ""), id);
+                objects.insert(path.clone(), body[media..end].to_vec());
+                self.names.lock().unwrap().insert(path, name);
+                ("200 OK", json!({ "id": id }).to_string().into_bytes())
+            }
+            "GET" if key.ends_with("/drive/v3/files") => {
+                let url = Url::parse(&format!("http://stub/?{}", query)).unwrap();
+                let q = url.query_pairs().find(|(k, _)| k == "q").unwrap().1;
+                let name = q.split('\'').nth(1).unwrap_or_default();
+                let mut ids: Vec<_> = self
+                    .names
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|(path, n)| *n == name && objects.contains_key(*path))
+                    .map(|(path, _)| json!({ "id": path.rsplit('/').next() }))
+                    .collect();
+                ids.sort_by_key(|id| id.to_string());
+                ("200 OK", json!({ "files": ids }).to_string().into_bytes())
+            }
+            "PUT" => {
+                objects.insert(key, body.clone());
+                ("200 OK", Vec::new())
+            }
+            "GET" => match objects.get(&key) {
+                Some(o) => ("200 OK", o.clone()),
+                None => ("404 Not Found", Vec::new()),
+            },
+            "DELETE" if self.denied.lock().unwrap().contains(&key) => ("403 Forbidden", Vec::new()),
+            "DELETE" => match objects.remove(&key) {
+                Some(_) => ("204 No Content", Vec::new()),
+                None => ("404 Not Found", Vec::new()),
+            },
+            "POST" if query.starts_with("delete") => {
+                let denied = self.denied.lock().unwrap();
+                let body = String::from_utf8_lossy(&body);
+                let errors: String = body
+                    .split("<Key>")
+                    .skip(1)
+                    .filter_map(|k| k.split("</Key>").next())
+                    .filter_map(|k| {
+                        let path = format!("{}/{}", key, k);
+                        if denied.contains(&path) {
+                            Some(format!(
+                                "<Error><Key>{}</Key><Code>AccessDenied</Code><Message>Access Denied</Message></Error>",
+                                k
+                            ))
+                        } else {
+                            objects.remove(&path);
+                            None
+                        }
+                    })
+                    .collect();
+                let result = format!("<DeleteResult>{}</DeleteResult>", errors);
+                ("200 OK", result.into_bytes())
+            }
+            _ => ("200 OK", Vec::new()),
+        };
+        self.requests.lock().unwrap().push(Request {
+            method,
+            path,
+            headers,
+            body,
+        });
+
+        let mut stream = stream;
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            response.len()
+        )
+        .unwrap();
+        stream.write_all(&response).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+use std::env;
+use std::io::Read;
+
+use log::*;
+use reqwest;
+use reqwest::{Method, Url};
+
+use crate::chunk;
+use crate::crypto::Hash;
+use crate::remote::{check, deleted, AccountId, Provider, ProviderError};
+
+#[derive(Debug, Clone)]
+pub enum Auth {
+    Anonymous,
+    Basic {
+        user: String,
+        password: Option<String>,
+    },
+    Bearer(String),
+}
+
+/// Stores chunks as resources named by their remote names under a WebDAV collection
+#[derive(Debug)]
+pub struct WebDav {
+    base: Url,
+    auth: Auth,
+    client: reqwest::Client,
+}
+
+impl WebDav {
+    pub fn new(mut base: Url, auth: Auth) -> WebDav {
+        if !base.path().ends_with('/') {
+            let path = format!("{}/", base.path());
+            base.set_path(&path);
+        }
+        WebDav {
+            base,
+            auth,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Credentials embedded into url are used for basic auth, otherwise
+    /// `WEBDAV_TOKEN` environment variable is used as a bearer token if set
+    pub fn from_url(url: &str) -> Result<WebDav, ProviderError> {
+        let mut base = Url::parse(url)
+            .map_err(|e| ProviderError::Invalid(format!("WebDAV url {}: {}", url, e)))?;
+        let auth = if !base.username().is_empty() {
+            let auth = Auth::Basic {
+                user: base.username().to_owned(),
+                password: base.password().map(str::to_owned),
+            };
+            base.set_username("").unwrap();
+            base.set_password(None).unwrap();
+            auth
+        } else if let Ok(token) = env::var("WEBDAV_TOKEN") {
+            Auth::Bearer(token)
+        } else {
+            Auth::Anonymous
+        };
+        Ok(WebDav::new(base, auth))
+    }
+
+    fn request(&self, method: Method, h: &Hash) -> reqwest::RequestBuilder {
+        let url = self.base.join(&h.to_string()).unwrap();
+        let req = self.client.request(method, url);
+        match &self.auth {
+            Auth::Anonymous => req,
+            Auth::Basic { user, password } => req.basic_auth(user, password.as_ref()),
+            Auth::Bearer(token) => req.bearer_auth(token),
+        }
+    }
+}
+
+impl Provider for WebDav {
+    fn publish(&mut self, s: &chunk::Chunk) -> Result<AccountId, ProviderError> {
+        let res = self
+            .request(Method::PUT, &s.name)
+            .body(s.chunk.to_vec())
+            .send()?;
+        debug!("{:?}", res);
+        check(res)?;
+        Ok(0)
+    }
+
+    fn receive(&mut self, c: &chunk::Stored) -> Result<chunk::Data, ProviderError> {
+        let res = self.request(Method::GET, &c.name).send()?;
+        debug!("{:?}", res);
+        let mut r = Vec::new();
+        check(res)?.read_to_end(&mut r)?;
+        Ok(r)
+    }
+
+    fn delete(&mut self, cs: &[chunk::Stored]) -> Result<(), ProviderError> {
+        for c in cs {
+            let res = self.request(Method::DELETE, &c.name).send()?;
+            debug!("{:?}", res);
+            deleted(check(res))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::WebDav;
+    use crate::chunk;
+    use crate::remote::stub::Stub;
+    use crate::remote::{Provider, ProviderError};
+    use crate::testing::publish_receive;
+
+    #[test]
+    fn publish_receive_delete() {
+        let stub = Stub::default();
+        let url = stub.serve().replace("http://", "http://user:pass@");
+        let mut dav = WebDav::from_url(&format!("{}/dav", url)).unwrap();
+        let stored = publish_receive(&mut dav, &[5u8; chunk::CHUNK_SIZE]);
+        let path = format!("/dav/{}", stored.hash);
+        assert!(stub.objects.lock().unwrap().contains_key(&path));
+
+        dav.delete(&[stored.clone()]).unwrap();
+        assert!(stub.objects.lock().unwrap().is_empty());
+        match dav.receive(&stored) {
+            Err(ProviderError::NotFound) => {}
+            r => panic!("Unexpected {:?}", r.map(|_| ())),
+        }
+        let requests = stub.requests.lock().unwrap();
+        assert_eq!(
+            requests
+                .iter()
+                .map(|r| r.method.as_str())
+                .collect::<Vec<_>>(),
+            ["PUT", "GET", "DELETE", "GET"]
+        );
+        assert!(requests
+            .iter()
+            .all(|r| r.headers["authorization"] == "Basic dXNlcjpwYXNz"));
+    }
+}
@@ -1,43 +1,448 @@
-use std::cmp::min;
+use std::cmp::{max, min};
+use std::collections::HashSet;
+use std::fmt;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 
-use crate::chunk::CHUNK_SIZE;
+use log::*;
+
+use crate::chunk::{self, Chunking, Codec};
+use crate::crypto::Key;
+use crate::local::DbError;
+use crate::remote::compressed::compress;
+use crate::remote::ProviderError;
 use crate::{local, remote};
 
+/// Amount of chunk data received at once before writing it out
+const DOWNLOAD_BUFFER: usize = 64 << 20;
+
+#[derive(Debug)]
+pub enum ServiceError {
+    /// Local file can't be read or written
+    Io(io::Error),
+    Db(DbError),
+    Provider(ProviderError),
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServiceError::Io(e) => write!(f, "{}", e),
+            ServiceError::Db(e) => write!(f, "{}", e),
+            ServiceError::Provider(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for ServiceError {
+    fn from(e: io::Error) -> Self {
+        ServiceError::Io(e)
+    }
+}
+
+impl From<DbError> for ServiceError {
+    fn from(e: DbError) -> Self {
+        ServiceError::Db(e)
+    }
+}
+
+impl From<ProviderError> for ServiceError {
+    fn from(e: ProviderError) -> Self {
+        ServiceError::Provider(e)
+    }
+}
+
+/// Chunk counts of an uploaded file
+#[derive(Debug, PartialEq, Eq)]
+pub struct Uploaded {
+    pub published: usize,
+    /// Chunks which were already stored and not published again
+    pub deduplicated: usize,
+}
+
 pub struct Service<Db, Provider> {
     pub db: Db,
     pub provider: Provider,
+    /// Strategy uploaded files are split into chunks with
+    pub chunking: Chunking,
+    /// Key remote names of new chunks are derived with, chunks are named by
+    /// their hashes without it
+    pub naming: Option<Key>,
+    /// Codec new chunks are compressed with where it makes them shorter
+    pub codec: Codec,
 }
 
 impl<Db: local::Db, Provider: remote::Provider> Service<Db, Provider> {
-    // TODO?: return result
-    pub fn upload(&mut self, fname: &str, file: &str) {
+    pub fn upload(&mut self, fname: &str, file: &str) -> Result<Uploaded, ServiceError> {
         let mut content = Vec::new();
-        File::open(&file)
-            .unwrap_or_else(|_| panic!("Can't open {}", &file))
-            .read_to_end(&mut content)
-            .expect("Something happened during file reading");
-        let chunks = self.db.save(fname, &content);
-        for c in chunks {
-            self.provider.publish(&c);
+        File::open(&file)?.read_to_end(&mut content)?;
+        let mut saved = self.db.save(fname, &content, &self.chunking)?;
+        if let Some(ref key) = self.naming {
+            chunk::name_with(&mut saved.new, key);
+        }
+        compress(&mut saved.new, self.codec);
+        let accounts = match self.provider.publish_batch(&saved.new) {
+            Ok(accounts) => accounts,
+            Err(e) => {
+                // forget the file, so the upload can be retried, and delete
+                // the chunks which were published before the failure
+                let orphans = self.db.clean(fname)?;
+                if let Err(de) = self.provider.delete(&orphans) {
+                    warn!("{}: can't delete published chunks: {}", fname, de);
+                }
+                return Err(e.into());
+            }
+        };
+        for (c, account) in saved.new.iter().zip(accounts) {
+            self.db.place(c, account)?;
+        }
+        Ok(Uploaded {
+            published: saved.new.len(),
+            deduplicated: saved.deduplicated,
+        })
+    }
+
+    pub fn download(&mut self, fname: &str, newname: &str) -> Result<(), ServiceError> {
+        let (meta, chunks) = self.db.find(&fname)?;
+        let mut file = File::create(newname)?;
+        // the last chunk of legacy files is padded, so it's cut by the size
+        let mut left = meta.size;
+        for batch in chunks.chunks(max(DOWNLOAD_BUFFER / meta.chunking.max_len(), 1)) {
+            for (c, data) in batch.iter().zip(self.provider.receive_batch(batch)?) {
+                c.check(&data)?;
+                let n = min(left, data.len());
+                file.write_all(&data[..n])?;
+                left -= n;
+            }
         }
+        Ok(())
+    }
+
+    /// Chunks are deleted before the file is forgotten, so a failed removal
+    /// can be repeated
+    pub fn remove(&mut self, fname: &str) -> Result<(), ServiceError> {
+        let orphans = self.db.orphans(&fname)?;
+        self.provider.delete(&orphans)?;
+        self.db.clean(&fname)?;
+        Ok(())
     }
 
-    // TODO?: return result
-    pub fn download(&mut self, fname: &str, newname: &str) {
-        let (mut fsize, hash_list) = self.db.find(&fname).unwrap();
-        let mut file = File::create(newname).unwrap();
-        for h in hash_list {
-            file.write_all(&self.provider.receive(&h)[..min(fsize, CHUNK_SIZE)])
-                .unwrap();
-            fsize -= CHUNK_SIZE;
+    /// Publishes every stored chunk again, so it is encrypted and named with
+    /// the current key of the provider, and deletes objects of the old names,
+    /// returns the number of chunks
+    pub fn reencrypt(&mut self) -> Result<usize, ServiceError> {
+        let mut seen = HashSet::new();
+        let mut stored = Vec::new();
+        for (fname, _) in self.db.list()? {
+            let (_, chunks) = self.db.find(&fname)?;
+            stored.extend(chunks.into_iter().filter(|c| seen.insert(c.hash.clone())));
         }
+        let max_len = stored.iter().map(|c| c.len).max().unwrap_or(1);
+        for batch in stored.chunks(max(DOWNLOAD_BUFFER / max(max_len, 1), 1)) {
+            let mut chunks = Vec::with_capacity(batch.len());
+            for (c, data) in batch.iter().zip(self.provider.receive_batch(batch)?) {
+                c.check(&data)?;
+                chunks.push(chunk::Chunk {
+                    hash: c.hash.clone(),
+                    name: c.hash.clone(),
+                    chunk: data,
+                    idx: 0,
+                    codec: Codec::None,
+                });
+            }
+            if let Some(ref key) = self.naming {
+                chunk::name_with(&mut chunks, key);
+            }
+            compress(&mut chunks, self.codec);
+            let accounts = self.provider.publish_batch(&chunks)?;
+            for (c, account) in chunks.iter().zip(accounts) {
+                self.db.place(c, account)?;
+            }
+            let renamed: Vec<_> = batch
+                .iter()
+                .zip(&chunks)
+                .filter(|(s, c)| s.name != c.name)
+                .map(|(s, _)| s.clone())
+                .collect();
+            self.provider.delete(&renamed)?;
+        }
+        Ok(stored.len())
     }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::{Service, ServiceError, Uploaded};
+    use crate::chunk::{Chunking, Codec};
+    use crate::crypto::{self, Key, SALT_SIZE};
+    use crate::local::memory::Memory;
+    use crate::local::{Db, DbError};
+    use crate::remote::compressed::Compressed;
+    use crate::remote::directory::Directory;
+    use crate::remote::encrypted::{Encrypted, Mode};
+    use crate::remote::pool::Pool;
+    use crate::remote::ProviderError;
+    use crate::testing::{random_blob, tmpdir};
 
-    pub fn remove(&mut self, fname: &str) {
-        let (_, hash_list) = self.db.find(&fname).unwrap();
-        self.db.clean(&fname);
-        self.provider.delete(&hash_list);
+    #[test]
+    fn upload_download_remove() {
+        let root = tmpdir();
+        let accounts = (0..2)
+            .map(|i| {
+                (
+                    i + 1,
+                    vec![Directory::new(root.join(format!("account{}", i))).unwrap()],
+                )
+            })
+            .collect();
+        let mut service = Service {
+            db: Memory::new(),
+            provider: Pool::new(accounts, 2),
+            chunking: Chunking::cdc(1024),
+            naming: None,
+            codec: Codec::None,
+        };
+
+        let content = random_blob(20003);
+        let (src, dst) = (root.join("src"), root.join("dst"));
+        fs::write(&src, &content).unwrap();
+
+        service.upload("file", src.to_str().unwrap()).unwrap();
+        service.download("file", dst.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(&dst).unwrap(), content);
+
+        service.remove("file").unwrap();
+        assert!(service.db.find("file").is_err());
+        (0..2).for_each(|i| {
+            let account = root.join(format!("account{}", i));
+            assert_eq!(fs::read_dir(account).unwrap().count(), 0);
+        });
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn keep_shared_chunks() {
+        let root = tmpdir();
+        let mut service = Service {
+            db: Memory::new(),
+            provider: Directory::new(root.join("account")).unwrap(),
+            chunking: Chunking::Fixed(100),
+            naming: None,
+            codec: Codec::None,
+        };
+        let content = random_blob(1000);
+        let (src, dst) = (root.join("src"), root.join("dst"));
+        fs::write(&src, &content).unwrap();
+
+        let uploaded = service.upload("file1", src.to_str().unwrap()).unwrap();
+        assert_eq!(uploaded.published, 10);
+        let uploaded = service.upload("file2", src.to_str().unwrap()).unwrap();
+        assert_eq!(
+            uploaded,
+            Uploaded {
+                published: 0,
+                deduplicated: 10,
+            }
+        );
+        service.remove("file1").unwrap();
+        service.download("file2", dst.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(&dst).unwrap(), content);
+
+        service.remove("file2").unwrap();
+        assert_eq!(fs::read_dir(root.join("account")).unwrap().count(), 0);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn compress_chunks() {
+        let root = tmpdir();
+        let mut service = Service {
+            db: Memory::new(),
+            provider: Compressed::new(Directory::new(root.join("account")).unwrap()),
+            chunking: Chunking::Fixed(1000),
+            naming: None,
+            codec: Codec::Zstd,
+        };
+        let mut content = b"compresses well ".repeat(1000 / 16);
+        content.extend(random_blob(1500));
+        let (src, dst) = (root.join("src"), root.join("dst"));
+        fs::write(&src, &content).unwrap();
+
+        service.upload("file", src.to_str().unwrap()).unwrap();
+        let (_, stored) = service.db.find("file").unwrap();
+        let codecs: Vec<_> = stored.iter().map(|c| c.codec).collect();
+        assert_eq!(codecs, [Codec::Zstd, Codec::None, Codec::None]);
+        let published = fs::read(root.join("account").join(stored[0].name.to_string())).unwrap();
+        assert!(published.len() < stored[0].len / 4);
+
+        service.codec = Codec::None;
+        service.download("file", dst.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(&dst).unwrap(), content);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn keyed_names() {
+        let root = tmpdir();
+        let mut service = Service {
+            db: Memory::new(),
+            provider: Directory::new(root.join("account")).unwrap(),
+            chunking: Chunking::Fixed(100),
+            naming: Some(Key::derive(b"passphrase", &[0; SALT_SIZE])),
+            codec: Codec::None,
+        };
+        let content = random_blob(1000);
+        let (src, dst) = (root.join("src"), root.join("dst"));
+        fs::write(&src, &content).unwrap();
+
+        service.upload("file", src.to_str().unwrap()).unwrap();
+        let (_, stored) = service.db.find("file").unwrap();
+        for c in &stored {
+            assert_ne!(c.name, c.hash);
+            assert!(!root.join("account").join(c.hash.to_string()).exists());
+            assert!(root.join("account").join(c.name.to_string()).exists());
+        }
+        assert_eq!(stored[0].hash, crypto::hash(&content[..100]));
+
+        // names are kept in the db, so the key is not needed to find chunks
+        service.naming = None;
+        service.download("file", dst.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(&dst).unwrap(), content);
+        service.remove("file").unwrap();
+        assert_eq!(fs::read_dir(root.join("account")).unwrap().count(), 0);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn rotate_key() {
+        let root = tmpdir();
+        let (old, new) = (Key::random(), Key::random());
+        let account = root.join("account");
+        let encrypted =
+            |keys| Encrypted::new(Directory::new(&account).unwrap(), keys, Mode::Random);
+        let mut service = Service {
+            db: Memory::new(),
+            provider: encrypted(vec![old.clone()]),
+            chunking: Chunking::Fixed(100),
+            naming: Some(old.clone()),
+            codec: Codec::None,
+        };
+        let content = random_blob(1000);
+        let (src, dst) = (root.join("src"), root.join("dst"));
+        fs::write(&src, &content).unwrap();
+        service.upload("file1", src.to_str().unwrap()).unwrap();
+        service.upload("file2", src.to_str().unwrap()).unwrap();
+
+        service.provider = encrypted(vec![new.clone(), old]);
+        service.naming = Some(new.clone());
+        assert_eq!(service.reencrypt().unwrap(), 10);
+        assert_eq!(fs::read_dir(&account).unwrap().count(), 10);
+
+        service.provider = encrypted(vec![new]);
+        service.download("file2", dst.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(&dst).unwrap(), content);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn retry_failed_upload() {
+        let root = tmpdir();
+        let account = root.join("account");
+        let mut service = Service {
+            db: Memory::new(),
+            provider: Directory::new(&account).unwrap(),
+            chunking: Chunking::Fixed(100),
+            naming: None,
+            codec: Codec::None,
+        };
+        let content = random_blob(1000);
+        let (src, dst) = (root.join("src"), root.join("dst"));
+        fs::write(&src, &content).unwrap();
+
+        fs::remove_dir(&account).unwrap();
+        match service.upload("file", src.to_str().unwrap()) {
+            Err(ServiceError::Provider(ProviderError::NotFound)) => {}
+            r => panic!("Unexpected {:?}", r),
+        }
+        assert!(service.db.find("file").is_err());
+
+        fs::create_dir(&account).unwrap();
+        let uploaded = service.upload("file", src.to_str().unwrap()).unwrap();
+        assert_eq!(uploaded.published, 10);
+        service.download("file", dst.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(&dst).unwrap(), content);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn delete_published_on_failure() {
+        let root = tmpdir();
+        let mut service = Service {
+            db: Memory::new(),
+            provider: Directory::new(&root).unwrap(),
+            chunking: Chunking::Fixed(100),
+            naming: None,
+            codec: Codec::None,
+        };
+        let content = random_blob(1000);
+        let src = root.join("src");
+        fs::write(&src, &content).unwrap();
+        // the sixth chunk can't be written over a directory
+        let blocker = root.join(crypto::hash(&content[500..600]).to_string());
+        fs::create_dir(&blocker).unwrap();
+
+        assert!(service.upload("file", src.to_str().unwrap()).is_err());
+        assert!(service.db.find("file").is_err());
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 2);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn detect_damaged_chunks() {
+        let root = tmpdir();
+        let mut service = Service {
+            db: Memory::new(),
+            provider: Directory::new(&root).unwrap(),
+            chunking: Chunking::Fixed(100),
+            naming: None,
+            codec: Codec::None,
+        };
+        let content = random_blob(300);
+        let (src, dst) = (root.join("src"), root.join("dst"));
+        fs::write(&src, &content).unwrap();
+        service.upload("file", src.to_str().unwrap()).unwrap();
+
+        let damaged = root.join(crypto::hash(&content[100..200]).to_string());
+        fs::write(&damaged, random_blob(100)).unwrap();
+        match service.download("file", dst.to_str().unwrap()) {
+            Err(ServiceError::Provider(ProviderError::Mismatch(_))) => {}
+            r => panic!("Unexpected {:?}", r),
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn report_errors() {
+        let root = tmpdir();
+        let mut service = Service {
+            db: Memory::new(),
+            provider: Directory::new(&root).unwrap(),
+            chunking: Chunking::default(),
+            naming: None,
+            codec: Codec::None,
+        };
+        let dst = root.join("dst");
+        match service.upload("file", root.join("nofile").to_str().unwrap()) {
+            Err(ServiceError::Io(_)) => {}
+            r => panic!("Unexpected {:?}", r),
+        }
+        match service.download("file", dst.to_str().unwrap()) {
+            Err(ServiceError::Db(DbError::NotFound)) => {}
+            r => panic!("Unexpected {:?}", r),
+        }
+        assert!(!dst.exists());
+        fs::remove_dir_all(&root).unwrap();
     }
 }
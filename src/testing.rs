@@ -0,0 +1,44 @@
+//! Helpers shared by tests of the modules
+
+use std::fs;
+use std::path::PathBuf;
+
+use crate::chunk;
+use crate::crypto;
+use crate::remote::Provider;
+
+/// Creates an empty directory of a random name in the temporary one
+pub fn tmpdir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cloud-stash-{}", rand::random::<u64>()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn random_blob(sz: usize) -> Vec<u8> {
+    (0..sz).map(|_| rand::random()).collect()
+}
+
+/// Uncompressed chunk of the block named by its hash
+pub fn chunk_of(block: &[u8]) -> chunk::Chunk {
+    chunk::Chunk {
+        hash: crypto::hash(block),
+        name: crypto::hash(block),
+        chunk: block.to_vec(),
+        idx: 0,
+        codec: chunk::Codec::None,
+    }
+}
+
+/// Publishes the chunk of the block and checks that it's received back
+pub fn publish_receive<P: Provider>(p: &mut P, block: &[u8]) -> chunk::Stored {
+    let c = chunk_of(block);
+    let stored = chunk::Stored {
+        hash: c.hash.clone(),
+        name: c.name.clone(),
+        account: p.publish(&c).unwrap(),
+        len: block.len(),
+        codec: c.codec,
+    };
+    assert_eq!(&p.receive(&stored).unwrap()[..], block);
+    stored
+}